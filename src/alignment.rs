@@ -1,65 +1,324 @@
+use crate::constants;
 use crate::types_structs::Frag;
 use block_aligner::scan_block::*;
 use block_aligner::scores::*;
 use fxhash::FxHashMap;
-use crate::types_structs::{Genotype, GnPosition};
-//Only do realign around SNPs, will add around deletions later
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+use crate::types_structs::{Allele, Genotype, GnPosition, SnpPosition};
+
+//Flank sizes (bp) around a variant site for realignment. The flank grows by the length of an
+//adjacent homopolymer run, where ONT indel errors concentrate, up to MAX_FLANK. Sites with less
+//than MIN_FLANK bases on either side of the read or reference are not realigned.
+const BASE_FLANK: usize = 16;
+const MAX_FLANK: usize = 48;
+const MIN_FLANK: usize = 4;
+
+//Local haplotype flanks of a site for a read, and the read from the start of the left flank to
+//the end of the right flank of the longest allele. Shorter alleles are followed by reference bases from right_extension after the right flank,
+//so every allele's haplotype spans the whole query. mean_error is the mean base error
+//probability of the query from its base qualities.
+struct RealignWindow {
+    left_hap: Vec<u8>,
+    right_hap: Vec<u8>,
+    right_extension: Vec<u8>,
+    query: Vec<u8>,
+    mean_error: f64,
+}
+
+//A window to realign and the reference context it is aligned against.
+struct RealignJob {
+    frag_index: usize,
+    snp_pos: SnpPosition,
+    context: usize,
+    query: Vec<u8>,
+    mean_error: f64,
+}
+
+//Realign each read around each variant site against a local haplotype for every allele of the
+//site and keep the best allele if it is unambiguous. Nearby variants that the read was
+//genotyped at are put into the haplotypes, so a neighbouring indel does not penalise every
+//allele of the site equally. SNPs, MNPs and indels are realigned. The alignment scores replace
+//the base quality likelihoods of the site, so ties are kept as uncertain evidence.
+//
+//Reads are realigned as a batch: windows with the same site and local haplotypes share one
+//encoding of the haplotypes, and are aligned together with one aligner per thread.
 pub fn realign(
     ref_gn: &[u8],
-    frag: &mut Frag,
+    frags: &mut [&mut Frag],
     var_to_gn_pos: &Vec<GnPosition>,
-    gn_pos_to_allele: &FxHashMap<GnPosition, Vec<Genotype>>,
+    gn_pos_to_allele: &FxHashMap<GnPosition, Vec<Allele>>,
 ) {
-    let flank = 16;
     let block_size = 16;
     let gaps = Gaps {
         open: -2,
         extend: -1,
     };
-    let mut a = Block::<false, false>::new(2 * flank, 2 * flank , 2 * flank);
-    for (snp_pos, orig_geno) in frag.seq_dict.iter_mut() {
-        let snp_gn_pos = var_to_gn_pos[*snp_pos as usize - 1] as usize;
-        let snp_q_pos = frag.snp_pos_to_seq_pos[&(snp_pos)].1 as usize;
-        if !(flank > snp_gn_pos
-            || flank + snp_gn_pos >= ref_gn.len()
-            || flank > snp_q_pos
-            || flank + snp_q_pos >= frag.seq_string[0].len())
-        {
-            let mut ambiguous = true;
-            let mut ref_str = ref_gn[snp_gn_pos - flank..snp_gn_pos + flank].to_vec();
-            let alleles = &gn_pos_to_allele[&snp_gn_pos];
-            let mut best_score = i32::MIN;
-            let mut best_geno = 0;
-            let q = PaddedBytes::from_bytes::<NucMatrix>(
-                &frag.seq_string[0].slice(snp_q_pos - flank, snp_q_pos + flank).ascii(),
-                block_size,
-            );
-            for i in 0..alleles.len() {
-                
-                ref_str[flank] = alleles[i] as u8;
-                let r = PaddedBytes::from_bytes::<NucMatrix>(&ref_str, block_size);
-
-                //let a = Block::<false, false>::align(&q, &r, &NW1, gaps, block_size..=block_size, 0);
-
-                // Align with traceback, but no x drop threshold.
-//                let a =
-//                    Block::<false,false>::align(&q, &r, &NW1, gaps, block_size..=block_size, 0);
-                a.align(&q, &r, &NW1, gaps, block_size..=block_size, 0);
-                let res = a.res();
-                let score = res.score;
-                if score > best_score {
-                    best_score = score;
-                    best_geno = i as Genotype;
-                    ambiguous = false;
-                }
-                else if score == best_score{
-                    ambiguous = true;
+
+    let windows = frags
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(frag_index, frag)| {
+            let read_seq = frag.seq_string[0].to_ascii_vec();
+            let mut sites = frag.seq_dict.keys().copied().collect::<Vec<SnpPosition>>();
+            sites.sort();
+            sites
+                .into_iter()
+                .filter_map(|snp_pos| {
+                    realign_window(ref_gn, frag, &read_seq, var_to_gn_pos, gn_pos_to_allele, snp_pos)
+                        .map(|x| (frag_index, snp_pos, x))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    //Reference contexts (site, left haplotype, right haplotype, right extension) shared by the
+    //windows.
+    let mut context_ids = FxHashMap::default();
+    let mut jobs = vec![];
+    for (frag_index, snp_pos, window) in windows {
+        let num_contexts = context_ids.len();
+        let context = *context_ids
+            .entry((snp_pos, window.left_hap, window.right_hap, window.right_extension))
+            .or_insert(num_contexts);
+        jobs.push(RealignJob {
+            frag_index,
+            snp_pos,
+            context,
+            query: window.query,
+            mean_error: window.mean_error,
+        });
+    }
+    if jobs.is_empty() {
+        return;
+    }
+    let mut contexts = context_ids.into_iter().collect::<Vec<_>>();
+    contexts.sort_by_key(|x| x.1);
+
+    let encoded_haps = contexts
+        .par_iter()
+        .map(|((snp_pos, left_hap, right_hap, right_extension), _)| {
+            let alleles = &gn_pos_to_allele[&var_to_gn_pos[*snp_pos as usize - 1]];
+            let max_len = alleles.iter().map(|x| x.len()).max().unwrap();
+            alleles
+                .iter()
+                .map(|allele| {
+                    let mut hap = left_hap.clone();
+                    hap.extend_from_slice(allele);
+                    hap.extend_from_slice(right_hap);
+                    hap.extend_from_slice(&right_extension[..max_len - allele.len()]);
+                    PaddedBytes::from_bytes::<NucMatrix>(&hap, block_size)
+                })
+                .collect::<Vec<PaddedBytes>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut context_jobs = vec![vec![]; contexts.len()];
+    for (i, job) in jobs.iter().enumerate() {
+        context_jobs[job.context].push(i);
+    }
+    let max_query_len = jobs.iter().map(|x| x.query.len()).max().unwrap();
+    let max_hap_len = encoded_haps.iter().flatten().map(|x| x.len()).max().unwrap();
+
+    let job_scores = context_jobs
+        .par_iter()
+        .enumerate()
+        .map_init(
+            || {
+                (
+                    Block::<false, false>::new(max_query_len, max_hap_len, block_size),
+                    PaddedBytes::new::<NucMatrix>(max_query_len, block_size),
+                )
+            },
+            |(a, q), (context, job_indices)| {
+                //Reads often have identical windows at a site; align each distinct one once.
+                //Every allele is aligned against the same query, and all haplotypes of the
+                //context span it, so the scores of different alleles are comparable.
+                let mut window_scores: FxHashMap<&[u8], Vec<i32>> = FxHashMap::default();
+                let mut scores = vec![];
+                for i in job_indices.iter() {
+                    let job = &jobs[*i];
+                    let allele_scores = window_scores.entry(&job.query).or_insert_with(|| {
+                        q.set_bytes::<NucMatrix>(&job.query, block_size);
+                        encoded_haps[context]
+                            .iter()
+                            .map(|hap| {
+                                a.align(q, hap, &NW1, gaps, block_size..=block_size, 0);
+                                a.res().score
+                            })
+                            .collect()
+                    });
+                    scores.push((*i, allele_scores.clone()));
                 }
+                scores
+            },
+        )
+        .flatten()
+        .collect::<Vec<_>>();
 
-            }
-            if !ambiguous{
-                *orig_geno = best_geno;
+    for (i, scores) in job_scores {
+        let job = &jobs[i];
+        let best_score = *scores.iter().max().unwrap();
+        let ll_per_unit = score_to_ln_likelihood(job.mean_error);
+        let lls = scores
+            .iter()
+            .map(|x| OrderedFloat((x - best_score) as f64 * ll_per_unit))
+            .collect::<Vec<_>>();
+        let best = scores
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == best_score)
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        let frag = &mut frags[job.frag_index];
+        if best.len() == 1 {
+            frag.seq_dict.insert(job.snp_pos, best[0] as Genotype);
+        }
+        frag.allele_lls.insert(job.snp_pos, lls);
+    }
+}
+
+//Realignment window of a read at a site. None if the site is too close to the end of the read
+//or reference.
+fn realign_window(
+    ref_gn: &[u8],
+    frag: &Frag,
+    read_seq: &[u8],
+    var_to_gn_pos: &[GnPosition],
+    gn_pos_to_allele: &FxHashMap<GnPosition, Vec<Allele>>,
+    snp_pos: SnpPosition,
+) -> Option<RealignWindow> {
+    let snp_gn_pos = var_to_gn_pos[snp_pos as usize - 1];
+    let snp_q_pos = frag.snp_pos_to_seq_pos[&snp_pos].1;
+    let alleles = &gn_pos_to_allele[&snp_gn_pos];
+    if alleles.len() < 2 {
+        return None;
+    }
+    let ref_len = alleles[0].len();
+    let max_len = alleles.iter().map(|x| x.len()).max().unwrap();
+    let min_len = alleles.iter().map(|x| x.len()).min().unwrap();
+    if snp_gn_pos + ref_len + max_len - min_len > ref_gn.len() {
+        return None;
+    }
+
+    let left_flank = (BASE_FLANK + homopolymer_run(ref_gn, snp_gn_pos, false)).min(MAX_FLANK);
+    let right_flank =
+        (BASE_FLANK + homopolymer_run(ref_gn, snp_gn_pos + ref_len - 1, true)).min(MAX_FLANK);
+    let left_flank = left_flank.min(snp_gn_pos).min(snp_q_pos);
+    let right_flank = right_flank
+        .min(ref_gn.len() - snp_gn_pos - ref_len - (max_len - min_len))
+        .min(read_seq.len().saturating_sub(snp_q_pos + max_len));
+    if left_flank < MIN_FLANK || right_flank < MIN_FLANK {
+        return None;
+    }
+
+    let (left_hap, right_hap) = local_haplotype_flanks(
+        ref_gn,
+        frag,
+        var_to_gn_pos,
+        gn_pos_to_allele,
+        snp_pos,
+        (snp_gn_pos - left_flank, snp_gn_pos + ref_len + right_flank),
+    );
+    let q_end = snp_q_pos + max_len + right_hap.len();
+    if left_hap.len() > snp_q_pos || q_end > read_seq.len() {
+        return None;
+    }
+    let q_start = snp_q_pos - left_hap.len();
+    //Reads without base qualities get the same floor as qual_allele_lls.
+    let quals = &frag.qual_string[0];
+    let mean_error = if quals.len() == read_seq.len() {
+        quals[q_start..q_end]
+            .iter()
+            .map(|q| 10_f64.powf(q.saturating_sub(33) as f64 / -10.))
+            .sum::<f64>()
+            / (q_end - q_start) as f64
+    } else {
+        constants::MIN_ALLELE_ERROR
+    };
+    let window_end = snp_gn_pos + ref_len + right_flank;
+    Some(RealignWindow {
+        query: read_seq[q_start..q_end].to_vec(),
+        left_hap,
+        right_hap,
+        right_extension: ref_gn[window_end..window_end + max_len - min_len].to_vec(),
+        mean_error,
+    })
+}
+
+//Log-likelihood per unit of alignment score for a window with mean base error probability
+//mean_error. A mismatch costs two units and, with errors spread over the 3 other bases, changes
+//the likelihood of a base by (1 - e) / (e / 3).
+fn score_to_ln_likelihood(mean_error: f64) -> f64 {
+    let e = mean_error.clamp(constants::MIN_ALLELE_ERROR, 0.5);
+    (3. * (1. - e) / e).ln() / 2.
+}
+
+//Length of the run of bases equal to ref_gn[pos] next to pos, to the right or to the left.
+fn homopolymer_run(ref_gn: &[u8], pos: usize, right: bool) -> usize {
+    let base = ref_gn[pos];
+    if right {
+        ref_gn[pos + 1..].iter().take_while(|x| **x == base).count()
+    } else {
+        ref_gn[..pos].iter().rev().take_while(|x| **x == base).count()
+    }
+}
+
+//Reference sequence left and right of a site within window, with the alleles of other sites
+//in the window that the read was genotyped at put in. Sites overlapping the window boundary or
+//the site itself are left as reference.
+fn local_haplotype_flanks(
+    ref_gn: &[u8],
+    frag: &Frag,
+    var_to_gn_pos: &[GnPosition],
+    gn_pos_to_allele: &FxHashMap<GnPosition, Vec<Allele>>,
+    snp_pos: SnpPosition,
+    window: (GnPosition, GnPosition),
+) -> (Vec<u8>, Vec<u8>) {
+    let site_start = var_to_gn_pos[snp_pos as usize - 1];
+    let site_end = site_start + gn_pos_to_allele[&site_start][0].len();
+
+    //(start, end, allele) of neighbouring sites in the window, in order.
+    let mut neighbours = vec![];
+    let mut i = snp_pos as usize - 1;
+    while i > 0 && var_to_gn_pos[i - 1] >= window.0 {
+        i -= 1;
+    }
+    while i < var_to_gn_pos.len() && var_to_gn_pos[i] < window.1 {
+        let pos = i as SnpPosition + 1;
+        let gn_pos = var_to_gn_pos[i];
+        i += 1;
+        if pos == snp_pos {
+            continue;
+        }
+        if let Some(geno) = frag.seq_dict.get(&pos) {
+            let alleles = &gn_pos_to_allele[&gn_pos];
+            let end = gn_pos + alleles[0].len();
+            let overlaps_site = gn_pos < site_end && end > site_start;
+            let in_window = end <= window.1;
+            let after_previous = neighbours
+                .last()
+                .is_none_or(|x: &(usize, usize, &Allele)| x.1 <= gn_pos);
+            if !overlaps_site && in_window && after_previous {
+                neighbours.push((gn_pos, end, &alleles[*geno as usize]));
             }
         }
     }
+
+    let build = |start: GnPosition, end: GnPosition| {
+        let mut seq = vec![];
+        let mut pos = start;
+        for (n_start, n_end, allele) in neighbours.iter() {
+            if *n_start < start || *n_end > end {
+                continue;
+            }
+            seq.extend_from_slice(&ref_gn[pos..*n_start]);
+            seq.extend_from_slice(allele);
+            pos = *n_end;
+        }
+        seq.extend_from_slice(&ref_gn[pos..end]);
+        seq
+    };
+
+    (build(window.0, site_start), build(site_end, window.1))
 }
@@ -2,10 +2,13 @@ extern crate time;
 use clap::Parser;
 use devider::file_reader;
 use devider::dbg;
+use devider::bootstrap;
 use devider::consensus;
 use devider::parse_cmd_line;
 use devider::utils_frags;
+use devider::variant_caller;
 use std::fs;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::time::Instant;
 use devider::types_structs::*;
@@ -81,19 +84,17 @@ fn main() {
     let start_t_initial = Instant::now();
     log::info!("Preprocessing VCF/Reference");
     let start_t = Instant::now();
-    let mut all_contigs = file_reader::get_contigs_to_phase(&options.bam_file);
-    let mut main_bam = file_reader::get_bam_readers(&options);
-    log::debug!("Read BAM file successfully.");
-
+    //Load the reference first; it also creates the .fai needed to decode CRAM.
     let mut chrom_seqs = None;
 
-    let vcf_profile = file_reader::get_vcf_profile(&options.vcf_file, &all_contigs);
-    log::debug!("Read VCF successfully.");
     if options.reference_fasta != "" {
         chrom_seqs = Some(file_reader::get_fasta_seqs(&options.reference_fasta));
         log::debug!("Read reference fasta successfully.");
     }
-    log::debug!("Finished preprocessing in {:?}", Instant::now() - start_t);
+
+    let mut all_contigs = file_reader::get_contigs_to_phase(&options);
+    let mut main_bams = file_reader::get_bam_readers(&options);
+    log::debug!("Read BAM file successfully.");
 
     // Parse bed file and sequence ranges
     let mut bed_sequences = file_reader::get_bed_sequences(&options.bed_file);
@@ -138,6 +139,20 @@ fn main() {
     }
 
 
+    let vcf_profile = if let Some(vcf_file) = &options.vcf_file {
+        let vcf_profile = file_reader::get_vcf_profile(vcf_file, &all_contigs, &bed_sequences, &options);
+        log::debug!("Read VCF successfully.");
+        vcf_profile
+    } else {
+        if chrom_seqs.is_none() {
+            log::error!("A reference fasta (-r) is required to call variants without a VCF file. Exiting.");
+            std::process::exit(1);
+        }
+        log::info!("No VCF file provided; calling variants from the BAM file");
+        variant_caller::call_variants(&mut main_bams, chrom_seqs.as_mut().unwrap(), &contigs_to_phase, &options)
+    };
+    log::debug!("Finished preprocessing in {:?}", Instant::now() - start_t);
+
     fs::create_dir_all(&format!("{}/intermediate", contig_out_dir)).unwrap();
     let mut warn_first_length = true;
     for (contig, range) in contigs_to_phase.iter() {
@@ -171,59 +186,133 @@ fn main() {
             );
         }
 
-        let mut all_frags;
-        let frags_without_snps;
-        (all_frags, frags_without_snps) = file_reader::get_frags_from_bamvcf_rewrite(
-            &mut main_bam,
+        let group_frags = file_reader::get_frags_from_bamvcf_rewrite(
+            &mut main_bams,
             &vcf_profile,
             &options,
             &mut chrom_seqs,
             &contig,
-            *range
+            *range,
+            options.split_tag.as_deref(),
         );
 
-        log::debug!("Number of reads passing filtering: {}", all_frags.len());
-        if all_frags.len() == 0 {
-            log::debug!("Contig {} has no fragments", range_contig_str);
-            continue;
-        }
+        for (group, mut all_frags, _frags_without_snps) in group_frags {
+            //Each group of reads is phased on its own and written to its own subdirectory.
+            let group_options;
+            let options = if options.split_tag.is_some() {
+                let mut opts = options.clone();
+                opts.output_dir = format!("{}/{}", options.output_dir, group_dir_name(&group));
+                fs::create_dir_all(&format!("{}/intermediate", opts.output_dir)).unwrap();
+                log::info!("Phasing reads with {} tag {}", options.split_tag.as_ref().unwrap(), group);
+                group_options = opts;
+                &group_options
+            } else {
+                &options
+            };
 
-        if vcf_profile.vcf_snp_pos_to_gn_pos_map.contains_key(contig.as_str()) {
-            
-            let snp_to_genome_pos = &vcf_profile.vcf_snp_pos_to_gn_pos_map[contig.as_str()];
-
-            all_frags.sort();
-            for (i, frag) in all_frags.iter_mut().enumerate() {
-                frag.counter_id = i;
+            log::debug!("Number of reads passing filtering: {}", all_frags.len());
+            if all_frags.len() == 0 {
+                log::debug!("Contig {} has no fragments", range_contig_str);
+                continue;
             }
 
-            //Get last SNP on the genome covered over all fragments.
-            let length_gn = utils_frags::get_length_gn(&all_frags);
-            log::info!("Contig {} has {} SNPs", range_contig_str, length_gn);
+            if vcf_profile.vcf_snp_pos_to_gn_pos_map.contains_key(contig.as_str()) {
+            
+                let snp_to_genome_pos = &vcf_profile.vcf_snp_pos_to_gn_pos_map[contig.as_str()];
 
-            let mut final_frags;
-            final_frags = all_frags;
-            let dbg_frags : Vec<FragDBG> = final_frags.iter().map(|x| dbg::frag_to_dbgfrag(x, &options)).collect();
+                all_frags.sort();
+                for (i, frag) in all_frags.iter_mut().enumerate() {
+                    frag.counter_id = i;
+                }
 
-            let dbg_frags = dbg_frags[0..options.max_frags.min(dbg_frags.len())].to_vec();
-            log::debug!(
-                "Reading inputs, realigning time taken {:?}",
-                Instant::now() - start_t
-            );
+                //Get last SNP on the genome covered over all fragments.
+                let length_gn = utils_frags::get_length_gn(&all_frags);
+                log::info!("Contig {} has {} SNPs", range_contig_str, length_gn);
 
-            let final_partitions = dbg::devider_run(dbg_frags, &options, &snp_to_genome_pos, &contig, *range, &vcf_profile);
+                let mut final_frags;
+                final_frags = all_frags;
+                let dbg_frags : Vec<FragDBG> = final_frags.iter().map(|x| dbg::frag_to_dbgfrag(x, options)).collect();
 
-            if let Some(final_partitions) = final_partitions {
-                consensus::simple_consensus(
-                    &mut main_bam,
-                    &mut chrom_seqs, 
-                    (&contig, *range),
-                    &final_partitions,
-                    &options,
-                    &vcf_profile,
+                let dbg_frags = utils_frags::downsample_frags(dbg_frags, options);
+                log::debug!(
+                    "Reading inputs, realigning time taken {:?}",
+                    Instant::now() - start_t
                 );
+
+                let bootstrap_frags = if options.bootstrap > 0 {
+                    Some(dbg_frags.clone())
+                } else {
+                    None
+                };
+                let final_partitions = dbg::devider_run(dbg_frags, options, &snp_to_genome_pos, &contig, *range, &vcf_profile);
+
+                if let Some(final_partitions) = final_partitions {
+                    if let Some(bootstrap_frags) = bootstrap_frags {
+                        bootstrap::bootstrap_haplotypes(
+                            &bootstrap_frags,
+                            &final_partitions,
+                            options,
+                            &snp_to_genome_pos,
+                            &contig,
+                            *range,
+                            &vcf_profile,
+                        );
+                    }
+                    if options.split_tag.is_some() {
+                        write_group_abundances(&contig_out_dir, &group, (&contig, *range), &final_partitions);
+                    }
+                    consensus::simple_consensus(
+                        &mut main_bams,
+                        &mut chrom_seqs, 
+                        (&contig, *range),
+                        &final_partitions,
+                        options,
+                        &vcf_profile,
+                    );
+                }
             }
         }
     }
     log::info!("Total time taken is {:?}", Instant::now() - start_t_initial);
 }
+
+//Group names come from read tags; keep them safe to use as directory names.
+fn group_dir_name(group: &str) -> String {
+    group
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+//One row per group and haplotype over all groups, written to the top-level output directory.
+fn write_group_abundances(
+    out_dir: &str,
+    group: &str,
+    contig_range: (&str, Option<(usize, usize)>),
+    partitions: &[HapFinalResultString],
+) {
+    let abund_file = format!("{}/group_abundances.tsv", out_dir);
+    let exists = Path::new(&abund_file).exists();
+    let mut writer = BufWriter::new(
+        fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&abund_file)
+            .expect("Could not open group abundance file"),
+    );
+    if !exists {
+        writeln!(writer, "group\tcontig\trange\thaplotype\tabundance\tdepth\thapq").unwrap();
+    }
+    let range = match contig_range.1 {
+        Some((s, e)) => format!("{}-{}", s, e),
+        None => String::from("ALL-ALL"),
+    };
+    for (i, hap) in partitions.iter().enumerate() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{:.2}\t{:.2}\t{}",
+            group, contig_range.0, range, i, hap.relative_abundances, hap.depth, hap.hapq
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::constants;
+use crate::dbg;
+use crate::parse_cmd_line::Options;
+use crate::types_structs::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+//Rerun devider_run on options.bootstrap subsamples (see subsample_indices, seeded by options.seed)
+//of the reads and match each resample's haplotypes one-to-one to the haplotypes of the full data,
+//closest first. For each haplotype, append to bootstrap.tsv the fraction of resamples it is
+//recovered in and a 95% interval of its abundance (see abundance_interval). Resamples write their
+//outputs to intermediate/bootstrap_<i>.
+pub fn bootstrap_haplotypes(
+    dbg_frags: &[FragDBG],
+    haplotypes: &[HapFinalResultString],
+    options: &Options,
+    snp_pos_to_genome_pos: &Vec<usize>,
+    contig_name: &str,
+    range: Option<(usize, usize)>,
+    vcf_profile: &VcfProfile,
+) {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut abundances = vec![vec![]; haplotypes.len()];
+    for i in 0..options.bootstrap {
+        let resample = subsample_indices(dbg_frags.len(), &mut rng)
+            .into_iter()
+            .map(|x| dbg_frags[x].clone())
+            .collect::<Vec<FragDBG>>();
+
+        let mut resample_options = options.clone();
+        resample_options.output_dir = format!("{}/intermediate/bootstrap_{}", options.output_dir, i);
+        resample_options.output_reads = false;
+        std::fs::create_dir_all(format!("{}/intermediate", resample_options.output_dir)).unwrap();
+        log::debug!("Bootstrap resample {}", i);
+        let resample_haplotypes = dbg::devider_run(
+            resample,
+            &resample_options,
+            snp_pos_to_genome_pos,
+            contig_name,
+            range,
+            vcf_profile,
+        )
+        .unwrap_or_default();
+
+        let mut pairs = vec![];
+        for (j, hap) in haplotypes.iter().enumerate() {
+            for (l, resample_hap) in resample_haplotypes.iter().enumerate() {
+                if let Some(distance) = allele_distance(&hap.alleles, &resample_hap.alleles) {
+                    if distance <= constants::BOOTSTRAP_MATCH_DISTANCE {
+                        pairs.push((distance, j, l));
+                    }
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut matched = vec![None; haplotypes.len()];
+        let mut resample_used = vec![false; resample_haplotypes.len()];
+        for (_, j, l) in pairs {
+            if matched[j].is_none() && !resample_used[l] {
+                matched[j] = Some(l);
+                resample_used[l] = true;
+            }
+        }
+        for (j, l) in matched.into_iter().enumerate() {
+            abundances[j].push(l.map(|l| resample_haplotypes[l].relative_abundances));
+        }
+    }
+
+    let (start, end) = match range {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let bootstrap_file = Path::new(&options.output_dir).join("bootstrap.tsv");
+    let exists = bootstrap_file.exists();
+    let mut bootstrap_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&bootstrap_file)
+            .expect("Could not open bootstrap file"),
+    );
+    if !exists {
+        bootstrap_writer
+            .write_all(b"#contig\trange\thaplotype\tabundance\trecovery\tabundance_ci_low\tabundance_ci_high\tresamples\n")
+            .unwrap();
+    }
+    for (j, hap) in haplotypes.iter().enumerate() {
+        let recovered = abundances[j].iter().filter(|x| x.is_some()).count();
+        let (ci_low, ci_high) = abundance_interval(hap.relative_abundances, &abundances[j]);
+        writeln!(
+            bootstrap_writer,
+            "{}\t{}-{}\t{}\t{:.2}\t{:.3}\t{:.2}\t{:.2}\t{}",
+            contig_name,
+            start,
+            end,
+            j,
+            hap.relative_abundances,
+            recovered as f64 / options.bootstrap as f64,
+            ci_low,
+            ci_high,
+            options.bootstrap
+        )
+        .unwrap();
+    }
+}
+
+//Sorted indices of a subsample of m = constants::BOOTSTRAP_SUBSAMPLE_FRACTION * n of n reads,
+//drawn without replacement so that no read (and no counter_id) appears twice.
+fn subsample_indices(n: usize, rng: &mut StdRng) -> Vec<usize> {
+    let m = (n as f64 * constants::BOOTSTRAP_SUBSAMPLE_FRACTION).round() as usize;
+    let mut inds = rand::seq::index::sample(rng, n, m).into_vec();
+    inds.sort();
+    inds
+}
+
+//95% percentile interval of a haplotype's abundance from its abundances over the subsamples,
+//counting subsamples that miss it as 0. Abundances of m-out-of-n subsamples vary around the point
+//estimate by a factor sqrt((n - m) / m) of the full data's spread, so deviations are scaled back
+//by sqrt(m / (n - m)) before taking percentiles.
+fn abundance_interval(point: f64, abundances: &[Option<f64>]) -> (f64, f64) {
+    let fraction = constants::BOOTSTRAP_SUBSAMPLE_FRACTION;
+    let scale = (fraction / (1. - fraction)).sqrt();
+    let mut sorted = abundances
+        .iter()
+        .map(|x| (point + scale * (x.unwrap_or(0.) - point)).clamp(0., 100.))
+        .collect::<Vec<f64>>();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    (percentile(&sorted, 0.025), percentile(&sorted, 0.975))
+}
+
+//Fraction of the sites in both haplotypes where their alleles differ. Alleles are sorted by
+//genome position. None if they share no sites.
+fn allele_distance(alleles1: &[(GnPosition, Genotype)], alleles2: &[(GnPosition, Genotype)]) -> Option<f64> {
+    let mut i = 0;
+    let mut j = 0;
+    let mut shared = 0;
+    let mut different = 0;
+    while i < alleles1.len() && j < alleles2.len() {
+        if alleles1[i].0 < alleles2[j].0 {
+            i += 1;
+        } else if alleles2[j].0 < alleles1[i].0 {
+            j += 1;
+        } else {
+            shared += 1;
+            if alleles1[i].1 != alleles2[j].1 {
+                different += 1;
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    if shared == 0 {
+        None
+    } else {
+        Some(different as f64 / shared as f64)
+    }
+}
+
+//Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    sorted[((sorted.len() - 1) as f64 * q).round() as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsample_interval_covers_mixture_abundance() {
+        //700 reads of haplotype 0 and 300 of haplotype 1; the point estimate is 70%.
+        let n = 1000;
+        let mut rng = StdRng::seed_from_u64(0);
+        let abundances = (0..500)
+            .map(|_| {
+                let inds = subsample_indices(n, &mut rng);
+                assert!(inds.windows(2).all(|x| x[0] < x[1]));
+                Some(100. * inds.iter().filter(|x| **x < 700).count() as f64 / inds.len() as f64)
+            })
+            .collect::<Vec<_>>();
+        let (low, high) = abundance_interval(70., &abundances);
+        assert!(low < 70. && 70. < high, "{} {}", low, high);
+        //The 95% interval of a proportion of 0.7 over 1000 reads is about 70 +- 2.8.
+        assert!(high - low > 4. && high - low < 7.5, "{} {}", low, high);
+    }
+}
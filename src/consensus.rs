@@ -7,7 +7,7 @@ use bio::io::fasta::IndexedReader as FastaIndexedReader;
 use std::io::{BufWriter};
 
 pub fn simple_consensus(
-    main_bam: &mut bam::IndexedReader,
+    main_bams: &mut [bam::IndexedReader],
     _chrom_seqs: &mut Option<FastaIndexedReader<std::fs::File>>,
     contig_range: (&str, Option<(usize,usize)>),
     partition: &Vec<HapFinalResultString>,
@@ -24,26 +24,35 @@ pub fn simple_consensus(
     let start_s;
     let end_s;
     if let Some((start, end)) = contig_range.1{
-        main_bam.fetch((contig_range.0, start as i32, end as i32)).unwrap();
         start_s = format!("{}", start);
         end_s = format!("{}", end);
     }
     else{
-        main_bam.fetch(contig_range.0).unwrap();
         start_s = String::from("ALL");
         end_s = String::from("ALL");
     }
     let mut inv_index = FxHashMap::default();
     for i in 0..partition.len(){
-        for frag_name in partition[i].assigned_frags.iter(){
-            inv_index.insert(frag_name, i);
+        for (source, frag_name) in partition[i].assigned_frags.iter(){
+            inv_index.insert((*source, frag_name.as_str()), i);
         }
     }
-    for record in main_bam.records(){
-        //let id = record.unwrap().qname().to_string();
-        let id = String::from_utf8_lossy(record.as_ref().unwrap().qname()).into_owned();
-        if let Some(&i) = inv_index.get(&id){
-            record_partition[i].push(record.unwrap());
+    for (source, main_bam) in main_bams.iter_mut().enumerate(){
+        let fetched = if let Some((start, end)) = contig_range.1{
+            main_bam.fetch((contig_range.0, start as i32, end as i32))
+        }
+        else{
+            main_bam.fetch(contig_range.0)
+        };
+        if fetched.is_err(){
+            continue;
+        }
+        for record in main_bam.records(){
+            //let id = record.unwrap().qname().to_string();
+            let id = String::from_utf8_lossy(record.as_ref().unwrap().qname()).into_owned();
+            if let Some(&i) = inv_index.get(&(source, id.as_str())){
+                record_partition[i].push(record.unwrap());
+            }
         }
     }
 
@@ -116,7 +125,8 @@ pub fn simple_consensus(
     let bufwriter = BufWriter::new(std::fs::File::create(consensus_file).unwrap());
     let mut consensus_writer = bio::io::fasta::Writer::from_bufwriter(bufwriter);
     for (i, consensus_string) in consensus_strings.iter().enumerate(){
-        let id = format!("Contig:{},Range:{}-{},Haplotype:{},Abundance:{},Depth:{} SimpleConsensus", contig_range.0, start_s, end_s, i, partition[i].relative_abundances, partition[i].depth);
+        let recombinant = partition[i].recombinant.map_or(String::new(), |(a, b)| format!(",Recombinant:{}+{}", a, b));
+        let id = format!("Contig:{},Range:{}-{},Haplotype:{},Abundance:{},Depth:{},HAPQ:{}{} SimpleConsensus", contig_range.0, start_s, end_s, i, partition[i].relative_abundances, partition[i].depth, partition[i].hapq, recombinant);
         let seq = String::from_utf8(consensus_string.clone()).unwrap();
         consensus_writer.write(&id, None, seq.as_bytes()).unwrap();
     }
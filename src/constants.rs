@@ -13,6 +13,10 @@ pub const MERGE_CUTOFF: f64 = 0.95;
 pub const SAME_SNP_DENSITY_CUTOFF: f64 = 1. / 10000.;
 pub const DIST_COV_CUTOFF: GenotypeCount = OrderedFloat(1.5);
 pub const USE_QUAL_SCORES: bool = true;
+//Lower bound on the per-base error rate used for allele likelihoods, i.e. Q60.
+pub const MIN_ALLELE_ERROR: f64 = 1e-6;
+//Log-likelihood of a read's allele at a site a haplotype has no allele for: a coin flip.
+pub const MISSING_SITE_LN_LIKELIHOOD: f64 = -std::f64::consts::LN_2;
 pub const MERGE_SIMILAR_HAPLOGROUPS: bool = false;
 pub const SEPARATE_BROKEN_HAPLOGROUPS: bool = true;
 pub const WEIRD_SPLIT: bool = false;
@@ -20,5 +24,35 @@ pub const FLOW_CUTOFF_MULT: f64 = 100.;
 pub const HAPQ_CONSTANT: f64 = 40.;
 pub const MINIMUM_BLOCK_SIZE: usize = 500;
 pub const EXTENSION_BASES: usize = 25;
+//--multi-k: at most this many k increments, and stop once the number of unitigs changes by
+//less than this fraction between increments.
+pub const MULTI_K_ROUNDS: usize = 10;
+pub const MULTI_K_STABLE_FRACTION: f64 = 0.05;
+//Records per chunk and number of chunks queued when reading alignments.
+pub const RECORD_CHUNK_SIZE: usize = 10000;
+pub const RECORD_CHUNKS_IN_FLIGHT: usize = 2;
+//Hash shards that varmers are counted in when building the DBG.
+pub const VARMER_COUNT_SHARDS: usize = 64;
+//Bubbles in the unitig graph: branches are at most this many unitigs long, and at most this
+//many branches are listed per bubble.
+pub const MAX_BUBBLE_UNITIGS: usize = 10;
+pub const MAX_BUBBLE_BRANCHES: usize = 16;
+//remove_tips tests a tip's coverage as errors of its neighbour at this rate.
+pub const TIP_ERROR_RATE: f64 = 0.10;
+//EM of haplotype abundances stops after this many iterations or once no abundance changes by
+//more than the tolerance.
+pub const EM_MAX_ITERATIONS: usize = 1000;
+pub const EM_TOLERANCE: f64 = 1e-6;
+//A haplotype of a bootstrap resample matches one of the full data if their alleles differ at
+//<= this fraction of their shared sites.
+pub const BOOTSTRAP_MATCH_DISTANCE: f64 = 0.05;
+//Bootstrap resamples draw this fraction of the reads without replacement.
+pub const BOOTSTRAP_SUBSAMPLE_FRACTION: f64 = 0.8;
+//Recombinant detection: rate at which a haplotype's alleles differ from its parents', the
+//log-likelihood ratio (about ln 1000) from which a haplotype is flagged as recombinant, and the
+//largest fraction of shared sites at which a flagged haplotype may differ from its mosaic.
+pub const RECOMBINANT_ALLELE_ERROR: f64 = 0.01;
+pub const RECOMBINANT_MIN_LLR: f64 = 7.;
+pub const RECOMBINANT_MAX_DISTANCE: f64 = 0.02;
 
 pub const CONTIG_PLOIDY_HEADER: &str = "contig\taverage_straincount\twhole_contig_multiplicity\tapproximate_coverage_ignoring_indels\ttotal_vartig_bases_covered\taverage_straincount_min15hapq\taverage_straincount_min30hapq\taverage_straincount_min45hapq\tavg_err\n";
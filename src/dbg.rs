@@ -1,4 +1,8 @@
+use crate::constants;
+use crate::flow;
+use crate::recombination;
 use crate::parse_cmd_line;
+use crate::parse_cmd_line::PathMode;
 use fishers_exact::fishers_exact;
 use rust_lapper::*;
 use std::sync::Mutex;
@@ -10,11 +14,12 @@ use disjoint_sets::UnionFind;
 use fxhash::{FxHashMap, FxHashSet};
 use ordered_float::*;
 use statrs::distribution::{Binomial, DiscreteCDF};
+use std::borrow::Cow;
 use std::collections::VecDeque;
 use std::fmt;
+use std::hash::Hash;
 use std::io::{BufWriter, Write};
 use std::path::Path;
-use std::sync::Arc;
 
 pub fn devider_run(
     mut dbg_frags:Vec<FragDBG>,
@@ -107,13 +112,11 @@ pub fn devider_run(
         return None;
     }
 
-    //disable this for now
     log::trace!("Start k: {}", k);
-    let end = 0;
 
-    let dbg = dbg_from_frags(&dbg_frags, k, None, None, None);
+    let dbg = dbg_from_frags(&dbg_frags, k, None, None, None, options.gapped_varmers);
     log::debug!("Constructed DBG for k = {}", k);
-    let (mut kmer_count, used_snp_positions) = count_kmers(&dbg_frags, k);
+    let (mut kmer_count, used_snp_positions) = count_kmers(&dbg_frags, k, options.gapped_varmers);
     let num_snps_range = used_snp_positions.len();
     let total_cov = kmer_count.iter().fold(0, |acc, (_varmer, cov)| acc + cov);
     let min_cov = u64::max(
@@ -122,16 +125,18 @@ pub fn devider_run(
     );
     log::debug!("Minimum coverage for global filter is : {:?}", min_cov);
 
-    let mut dbg = filter_dbg(dbg, Some(min_cov), None, k, false, num_snps_range);
+    let dbg = filter_dbg(&dbg, Some(min_cov), None, k);
     print_dbg(&dbg, &format!("{}/intermediate/dbg.dot", options.output_dir));
+    print_gfa(&dbg, &snp_pos_to_genome_pos_new, &[], &format!("{}/intermediate/dbg.gfa", options.output_dir));
 
     let mut uni = get_unitigs(&dbg, k, false);
-    kmer_count.retain(|varmer, _cov| dbg.contains_key(varmer));
-    let step = 1;
+    kmer_count.retain(|varmer, _cov| dbg.find(varmer).is_some());
 
-    for l in (step..end + 1).step_by(step) {
-        dbg = dbg_from_frags(&dbg_frags, l + k, Some(dbg), Some(&uni), Some(step));
-        uni = get_unitigs(&dbg, k + l, false);
+    let mut end = 0;
+    if options.multi_k {
+        let max_k = utils_frags::get_avg_length_dbgf(&dbg_frags, 0.5).min(num_snps * 3 / 4);
+        (uni, end) = grow_k(&dbg_frags, dbg, uni, k, max_k, options.gapped_varmers);
+        log::debug!("Final k = {}", k + end);
     }
     print_dbg(&uni, format!("{}/intermediate/unitigs.dot", options.output_dir).as_str());
 
@@ -146,22 +151,27 @@ pub fn devider_run(
 
     //Unitigging
     let unitigs = uni;
+    let bubbles = find_bubbles(&unitigs, k + end);
+    log::debug!("Number of bubbles: {}", bubbles.len());
 
     //Query and clean
     log::debug!("Cleaning unitigs");
     let mut final_unitigs = unitigs;
+    let mut removals = vec![];
     for i in 1..3 {
-        let bad_unitigs = query_unitigs(&final_unitigs, i);
+        let (bad_unitigs, bad_removals): (Vec<_>, Vec<_>) = query_unitigs(&final_unitigs, i).into_iter().unzip();
         log::debug!("Number of bad unitigs {}", bad_unitigs.len());
-        let filtered_unitigs = filter_dbg(final_unitigs, None, Some(bad_unitigs), k + end, false, num_snps_range);
+        removals.extend(bad_removals);
+        let filtered_unitigs = filter_dbg(&final_unitigs, None, Some(bad_unitigs), k + end);
         print_dbg(&filtered_unitigs, format!("{}/intermediate/clean_dbg_{}.dot", options.output_dir, i).as_str());
         final_unitigs = get_unitigs(&filtered_unitigs, k + end, true);
         print_dbg(&final_unitigs, format!("{}/intermediate/clean_unitigs_{}.dot",options.output_dir, i).as_str());
     }
 
     //Remove tips again
-    let tips = remove_tips(&final_unitigs, options, k + end);
-    final_unitigs = filter_dbg(final_unitigs, None, Some(tips), k + end, false, num_snps_range);
+    let (tips, tip_removals): (Vec<_>, Vec<_>) = remove_tips(&final_unitigs, options, k + end).into_iter().unzip();
+    removals.extend(tip_removals);
+    final_unitigs = filter_dbg(&final_unitigs, None, Some(tips), k + end);
     print_dbg(&final_unitigs, format!("{}/intermediate/tips_removed_round2.dot", options.output_dir).as_str());
     final_unitigs = get_unitigs(&final_unitigs, k + end, true);
 
@@ -169,138 +179,33 @@ pub fn devider_run(
     //Remove small disconnected components that have length < 1.5 * k and coverage < mean_cov / 100
     let min_cov_small_disconnected = u64::max(total_cov / (num_snps_range as u64 - k as u64 + 1) / (coverage_divider) * 4, 2);
     log::trace!("Second round min cov :{}", min_cov_small_disconnected);
-    final_unitigs = filter_dbg(final_unitigs, Some(min_cov_small_disconnected), None, k + end, true, num_snps_range);
+    let (small, small_removals): (Vec<_>, Vec<_>) =
+        small_disconnected_unitigs(&final_unitigs, min_cov_small_disconnected, num_snps_range).into_iter().unzip();
+    removals.extend(small_removals);
+    final_unitigs = filter_dbg(&final_unitigs, None, Some(small), k + end);
     final_unitigs = get_unitigs(&final_unitigs, k + end, true);
 
-    let final_unitigs = clean_hanging_kmers(final_unitigs, k + end - 1);
+    let final_unitigs = clean_hanging_kmers(&final_unitigs, k + end - 1);
+    write_bubbles(&bubbles, &removals, &final_unitigs, &snp_pos_to_genome_pos_new, options, (contig_name, range));
+    write_removed_unitigs(&removals, &snp_pos_to_genome_pos_new, options, (contig_name, range));
     print_dbg(&final_unitigs, format!("{}/intermediate/cleaned_unitigs.dot", options.output_dir).as_str());
 
-    //Try aligning reads to graph
-    log::debug!("Aligning reads to graph of size {}", final_unitigs.len());
-    let vec_df_all: Vec<DictFrag> = dbg_frags
-        .iter()
-        .map(|frag| fragdbg_to_dictfrag(frag))
-        .collect();
-    let vec_df_graph: Vec<DictFrag> = final_unitigs
-        .iter()
-        .map(|(varmer, info)| DictFrag {
-            seq: varmer.iter().cloned().collect(),
-            seq_vec: varmer.clone(),
-            first_position: varmer.first().unwrap().0,
-            last_position: varmer.last().unwrap().0,
-            cov: info.coverage,
-        })
-        .collect();
-
-    let dict_frag_to_index = vec_df_graph
+    let paths = match options.path_mode {
+        PathMode::ReadPaths => get_read_paths(
+            &dbg_frags,
+            &final_unitigs,
+            min_cov,
+            k + end,
+            &snp_pos_to_genome_pos_new,
+            options,
+        )?,
+        PathMode::Flow => flow::flow_paths(&final_unitigs, min_cov),
+    };
+    let mut gfa_paths = paths
         .iter()
         .enumerate()
-        .map(|(i, frag)| (frag, i))
-        .collect::<FxHashMap<&DictFrag, usize>>();
-
-    let path_dict = Mutex::new(FxHashMap::default());
-    //for dict_frag in vec_df_all.iter() {
-    log::trace!("ALIGN TO GRAPH");
-    vec_df_all.into_par_iter().for_each(|dict_frag| {
-        print_varmer_d(&dict_frag, true);
-        let hits = get_hits(&dict_frag, &vec_df_graph, 100000, 10, true);
-        let dp_res = dp_hits(
-            &hits,
-            &dict_frag,
-            &final_unitigs,
-            10000,
-            -3.0,
-            false,
-            false,
-            GraphConstraint::RequireDagRescue,
-            1000,
-            k,
-            &FxHashSet::default()
-        );
-        let varmers = varmers_from_dp_res(&dp_res, 0.2);
-        log::trace!("HITS: {:?}", hits.len());
-        log::trace!("4P RES: {:?}", dp_res.score);
-        for varmer in varmers.iter() {
-            print_varmer_d(varmer, true);
-        }
-        log::trace!("FIN DP RES");
-        *path_dict.lock().unwrap().
-            entry(varmers).or_insert(0) += 1;
-    });
-
-    let mut unitig_paths = vec![];
-    let path_dict = path_dict.into_inner().unwrap();
-    if path_dict.is_empty() {
-        log::error!("No paths found. Exiting.");
-        return None;
-    }
-    let mut counts = path_dict.iter().map(|(_, count)| *count).collect::<Vec<_>>();
-    counts.sort();
-    let median_count = counts[counts.len() / 2];
-
-    for (varmers, count) in path_dict.into_iter() {
-        log::trace!("INITIAL PATH COUNT: {}", count);
-        for varmer in varmers.iter() {
-            print_varmer_d(varmer, true);
-        }
-        if varmers.len() == 0 {
-            continue;
-        }
-        if count < (min_cov - 1).min(3).min(median_count) {
-            continue;
-        }
-        let path = VarmerPath {
-            first: varmers.first().unwrap().first_position,
-            last: varmers.last().unwrap().last_position,
-            varmers,
-            total_avg_cov: count,
-        };
-        unitig_paths.push(path);
-    }
-
-    log::debug!("Number of total paths: {}", unitig_paths.len());
-    let integer_paths = get_outside_paths_and_integers(&unitig_paths, &dict_frag_to_index);
-    log::debug!("Number of candidate outside paths: {}", integer_paths.len());
-    let assembly_graph = get_assembly_integer_graph(&integer_paths);
-
-    print_dbg(&assembly_graph, format!("{}/intermediate/assembly_graph.dot", options.output_dir).as_str());
-
-    let integer_unitigs = get_unitigs(&assembly_graph, 1, true);
-    //let integer_unitigs = assembly_graph;
-    let mut paths = vec![];
-    for (int_unitig, info) in integer_unitigs.iter() {
-        let path_as_df = int_unitig
-            .iter()
-            .map(|(_, x)| &vec_df_graph[(*x) as usize])
-            .collect::<Vec<_>>();
-        let min_cov_path = path_as_df.iter().map(|x| x.cov).min().unwrap();
-        if min_cov_path / 3 > info.coverage {
-            log::trace!(
-                "PATH ALIGN VS UNITIG COV CUTOFF - FAILED -- MIN COV PATH: {} INFO COV: {}",
-                min_cov_path,
-                info.coverage
-            );
-            for df in path_as_df.iter() {
-                print_varmer_d(df, true);
-            }
-        } else {
-            log::trace!(
-                "PATH ALIGN VS UNITIG COV CUTOFF - INTEGER UNITIG PASSED -- MIN COV PATH: {} INFO COV: {}",
-                min_cov_path,
-                info.coverage
-            );
-            for df in path_as_df.iter() {
-                print_varmer_d(df, true);
-            }
-            let path_as_varmers = int_unitig
-                .iter()
-                .map(|(_, x)| (&vec_df_graph[(*x) as usize].seq_vec, info.coverage as usize))
-                .collect::<Vec<_>>();
-            paths.push(path_as_varmers);
-        }
-    }
-
-    log::debug!("Number of candidate integer unitig paths passing filters: {}", paths.len());
+        .map(|(i, path)| (format!("candidate_{}", i), path.iter().map(|x| x.0).collect::<Vec<_>>()))
+        .collect::<Vec<_>>();
     let mut hap_path_results = get_path_haps(&dbg_frags, &final_unitigs, paths, num_snps, options);
     print_final_hap_results(
         &hap_path_results,
@@ -357,7 +262,7 @@ pub fn devider_run(
             let mut unassigned;
             loop{
                 log::debug!("Semifinal consensus");
-                let (final_results, unassigned_loop) = consensus(
+                let (mut final_results, unassigned_loop) = consensus(
                     &hap_path_results,
                     num_snps,
                     &snp_pos_to_genome_pos_new,
@@ -369,6 +274,8 @@ pub fn devider_run(
                     resolution
                 );
                 unassigned = unassigned_loop;
+                let all_posteriors = all_hap_posteriors(&dbg_frags, &final_results);
+                compute_hapqs(&mut final_results, &all_posteriors, &final_unitigs, options.min_posterior);
 
                 let final_results_filtered = filter_final_haplotypes(final_results, options);
                 //let final_results_filtered = final_results;
@@ -380,7 +287,16 @@ pub fn devider_run(
                 }
             }
 
-            let final_results_filtered = hap_path_results;
+            let mut final_results_filtered = hap_path_results;
+            let all_posteriors = all_hap_posteriors(&dbg_frags, &final_results_filtered);
+            compute_hapqs(&mut final_results_filtered, &all_posteriors, &final_unitigs, options.min_posterior);
+            em_abundances(&mut final_results_filtered, &dbg_frags);
+            recombination::detect_recombinants(
+                &mut final_results_filtered,
+                &snp_pos_to_genome_pos_new,
+                options,
+                (contig_name, range),
+            );
             let output_reads = if options.output_reads {
                 Some("reads.fq")
             } else {
@@ -398,6 +314,7 @@ pub fn devider_run(
                 vcf_profile,
                 &snp_pos_to_genome_pos_new,
             );
+            write_read_posteriors(&final_results_filtered, &dbg_frags, &all_posteriors, options, (contig_name, range));
 
             log::debug!("Final consensus");
             let _only_for_printing = consensus(
@@ -417,6 +334,16 @@ pub fn devider_run(
         hap_path_results = final_results_consensus;
     }
 
+    for (i, res) in hap_path_results.iter().enumerate() {
+        gfa_paths.push((format!("haplotype_{}", i), res.path.clone()));
+    }
+    print_gfa(
+        &final_unitigs,
+        &snp_pos_to_genome_pos_new,
+        &gfa_paths,
+        format!("{}/intermediate/cleaned_unitigs.gfa", options.output_dir).as_str(),
+    );
+
     let mut final_results_strings = vec![];
 
     for res in hap_path_results.iter() {
@@ -424,22 +351,186 @@ pub fn devider_run(
         let depth = res.depth;
         let mut read_ids = vec![];
         for frag in res.assigned_frags.iter() {
-            read_ids.push(frag.id.clone());
+            read_ids.push((frag.source, frag.id.clone()));
         }
 
         let hap_res_str = HapFinalResultString {
             relative_abundances: abund,
             depth,
+            hapq: res.hapq.unwrap_or(0),
+            recombinant: res.recombinant,
+            alleles: res
+                .path_frag
+                .seq_vec
+                .iter()
+                .map(|(pos, geno)| (snp_pos_to_genome_pos_new[*pos as usize - 1], *geno))
+                .collect(),
             assigned_frags: read_ids,
         };
         
         final_results_strings.push(hap_res_str);
     }
 
+    if options.bam_file.len() > 1 {
+        write_read_sources(&final_results_strings, options, (contig_name, range));
+    }
+
     return Some(final_results_strings);
 
 }
 
+//Candidate haplotype paths from the unitig paths that reads align to: read paths are merged
+//into outside paths (paths not contained in another), then into an assembly graph whose
+//unitigs are the candidates.
+fn get_read_paths(
+    dbg_frags: &[FragDBG],
+    final_unitigs: &VarmerGraph,
+    min_cov: u64,
+    k: usize,
+    snp_pos_to_genome_pos: &[usize],
+    options: &Options,
+) -> Option<Vec<Vec<(NodeId, usize)>>> {
+    log::debug!("Aligning reads to graph of size {}", final_unitigs.len());
+    let vec_df_all: Vec<DictFrag> = dbg_frags
+        .iter()
+        .map(fragdbg_to_dictfrag)
+        .collect();
+    let vec_df_graph: Vec<DictFrag> = final_unitigs
+        .ids()
+        .map(|node| {
+            let varmer = final_unitigs.node(node);
+            DictFrag {
+                seq: varmer.iter().cloned().collect(),
+                seq_vec: varmer.to_vec(),
+                first_position: varmer.first().unwrap().0,
+                last_position: varmer.last().unwrap().0,
+                cov: final_unitigs.coverage[node as usize],
+            }
+        })
+        .collect();
+
+    let graph_index = dict_frag_index(&vec_df_graph);
+    let dict_frag_to_index = vec_df_graph
+        .iter()
+        .enumerate()
+        .map(|(i, frag)| (frag, i))
+        .collect::<FxHashMap<&DictFrag, usize>>();
+
+    let path_dict = Mutex::new(FxHashMap::default());
+    //for dict_frag in vec_df_all.iter() {
+    log::trace!("ALIGN TO GRAPH");
+    vec_df_all.into_par_iter().for_each(|dict_frag| {
+        print_varmer_d(&dict_frag, true);
+        let hits = get_hits(&dict_frag, &vec_df_graph, &graph_index, 100000, 10, true);
+        let dp_res = dp_hits(
+            &hits,
+            &dict_frag,
+            final_unitigs,
+            10000,
+            -3.0,
+            false,
+            false,
+            GraphConstraint::RequireDagRescue,
+            1000,
+            k,
+            &FxHashSet::default()
+        );
+        let varmers = varmers_from_dp_res(&dp_res, 0.2);
+        log::trace!("HITS: {:?}", hits.len());
+        log::trace!("4P RES: {:?}", dp_res.score);
+        for varmer in varmers.iter() {
+            print_varmer_d(varmer, true);
+        }
+        log::trace!("FIN DP RES");
+        *path_dict.lock().unwrap().
+            entry(varmers).or_insert(0) += 1;
+    });
+
+    let mut unitig_paths = vec![];
+    let path_dict = path_dict.into_inner().unwrap();
+    if path_dict.is_empty() {
+        log::error!("No paths found. Exiting.");
+        return None;
+    }
+    let mut counts = path_dict.values().copied().collect::<Vec<_>>();
+    counts.sort();
+    let median_count = counts[counts.len() / 2];
+
+    for (varmers, count) in path_dict.into_iter() {
+        log::trace!("INITIAL PATH COUNT: {}", count);
+        for varmer in varmers.iter() {
+            print_varmer_d(varmer, true);
+        }
+        if varmers.is_empty() {
+            continue;
+        }
+        if count < (min_cov - 1).min(3).min(median_count) {
+            continue;
+        }
+        let path = VarmerPath {
+            first: varmers.first().unwrap().first_position,
+            last: varmers.last().unwrap().last_position,
+            varmers,
+            total_avg_cov: count,
+        };
+        unitig_paths.push(path);
+    }
+
+    log::debug!("Number of total paths: {}", unitig_paths.len());
+    let integer_paths = get_outside_paths_and_integers(&unitig_paths, &dict_frag_to_index);
+    log::debug!("Number of candidate outside paths: {}", integer_paths.len());
+    let assembly_graph = get_assembly_integer_graph(&integer_paths);
+
+    print_dbg(&assembly_graph, format!("{}/intermediate/assembly_graph.dot", options.output_dir).as_str());
+    print_gfa(
+        &integer_graph_to_varmers(&assembly_graph, &vec_df_graph),
+        snp_pos_to_genome_pos,
+        &[],
+        format!("{}/intermediate/assembly_graph.gfa", options.output_dir).as_str(),
+    );
+
+    let integer_unitigs = get_unitigs(&assembly_graph, 1, true);
+    //let integer_unitigs = assembly_graph;
+    let mut paths = vec![];
+    for node in integer_unitigs.ids() {
+        let int_unitig = integer_unitigs.node(node);
+        let coverage = integer_unitigs.coverage[node as usize];
+        let path_as_df = int_unitig
+            .iter()
+            .map(|(_, x)| &vec_df_graph[(*x) as usize])
+            .collect::<Vec<_>>();
+        let min_cov_path = path_as_df.iter().map(|x| x.cov).min().unwrap();
+        if min_cov_path / 3 > coverage {
+            log::trace!(
+                "PATH ALIGN VS UNITIG COV CUTOFF - FAILED -- MIN COV PATH: {} INFO COV: {}",
+                min_cov_path,
+                coverage
+            );
+            for df in path_as_df.iter() {
+                print_varmer_d(df, true);
+            }
+        } else {
+            log::trace!(
+                "PATH ALIGN VS UNITIG COV CUTOFF - INTEGER UNITIG PASSED -- MIN COV PATH: {} INFO COV: {}",
+                min_cov_path,
+                coverage
+            );
+            for df in path_as_df.iter() {
+                print_varmer_d(df, true);
+            }
+            //Unitig indices in vec_df_graph are the node IDs of final_unitigs.
+            let path_as_nodes = int_unitig
+                .iter()
+                .map(|(_, x)| (*x as NodeId, coverage as usize))
+                .collect::<Vec<_>>();
+            paths.push(path_as_nodes);
+        }
+    }
+
+    log::debug!("Number of candidate integer unitig paths passing filters: {}", paths.len());
+    Some(paths)
+}
+
 fn filter_final_haplotypes<'a>(
     final_results: Vec<HapFinalResult<'a>>,
     options: &'a Options,
@@ -454,6 +545,10 @@ fn filter_final_haplotypes<'a>(
             log::debug!("Haplotype {} has average coverage depth of {}x which is less than the minimum depth of {}x. Skipping", i, res.depth, options.min_cov);
             continue;
         }
+        if let Some(hapq) = res.hapq.filter(|x| *x < options.min_hapq) {
+            log::debug!("Haplotype {} has HAPQ {} which is less than the minimum HAPQ of {}. Skipping", i, hapq, options.min_hapq);
+            continue;
+        }
         filtered_results.push(res.clone());
     }
     return filtered_results;
@@ -520,6 +615,24 @@ fn consensus<'a>(
             .unwrap();
     }
     consensus_file.write(b"\n").unwrap();
+    if hap_path_results.iter().all(|x| x.hapq.is_some()) {
+        consensus_file.write_all(b"HAPQ").unwrap();
+        for res in hap_path_results.iter() {
+            consensus_file.write_all(format!("\t{}", res.hapq.unwrap()).as_bytes()).unwrap();
+        }
+        consensus_file.write_all(b"\n").unwrap();
+    }
+    if hap_path_results.iter().all(|x| x.em_abundance.is_some()) {
+        consensus_file.write_all(b"ABUNDANCE").unwrap();
+        for res in hap_path_results.iter() {
+            consensus_file.write_all(format!("\t{:.2}", res.relative_abundances).as_bytes()).unwrap();
+        }
+        consensus_file.write_all(b"\nEM_ABUNDANCE").unwrap();
+        for res in hap_path_results.iter() {
+            consensus_file.write_all(format!("\t{:.2}", res.em_abundance.unwrap()).as_bytes()).unwrap();
+        }
+        consensus_file.write_all(b"\n").unwrap();
+    }
     for i in 1..snps + 1 {
         consensus_file
             .write(format!("{}", snp_pos_to_genome_pos[i - 1] + 1).as_bytes())
@@ -583,8 +696,12 @@ fn consensus<'a>(
         let res = new_final_results_map.entry(root).or_insert(HapFinalResult {
             relative_abundances: 0.,
             depth: 0.,
+            hapq: None,
+            em_abundance: None,
+            recombinant: None,
             assigned_frags: vec![],
             path_frag: DictFrag::default(),
+            path: hap_path_results[root].path.clone(),
         });
 
         if res.path_frag.seq.is_empty() {
@@ -614,7 +731,8 @@ fn consensus<'a>(
     }
 
     let mut final_results_consensus = new_final_results_map.into_values().collect::<Vec<_>>();
-    let unassigned = reassign_frags(dbg_frags, &mut final_results_consensus, true);
+    let all_posteriors = all_hap_posteriors(dbg_frags, &final_results_consensus);
+    let unassigned = reassign_frags(dbg_frags, &all_posteriors, &mut final_results_consensus, true, options.min_posterior);
 
     for res in final_results_consensus.iter_mut() {
         if res.assigned_frags.is_empty() {
@@ -678,7 +796,9 @@ pub fn frag_to_dbgfrag(frag: &Frag, options: &Options) -> FragDBG {
         last_position,
         snp_pos_to_seq_pos: frag.snp_pos_to_seq_pos.clone(),
         qual_dict: frag.qual_dict.clone(),
+        allele_lls: frag.allele_lls.clone(),
         forward_strand: frag.forward_strand,
+        source: frag.source,
     };
     toret
 }
@@ -695,88 +815,44 @@ impl fmt::Display for VarmerPath {
     }
 }
 
-fn get_edges_varmers(dbg: &mut FxHashMap<VarMer, DBGInfo>, k: usize) {
-    let mut suffixes: FxHashMap<VarMer, Vec<Arc<VarMer>>> = FxHashMap::default();
-    let mut prefixes: FxHashMap<VarMer, Vec<Arc<VarMer>>> = FxHashMap::default();
-
-    for (varmer, _info) in dbg.iter() {
-        //dbg!(varmer.len(),k);
-        let suffix = varmer[varmer.len() - k + 1..].to_vec();
-        let prefix = varmer[..k - 1].to_vec();
-        let varmer_ref = Arc::new(varmer.clone());
-
-        suffixes
-            .entry(suffix)
-            .or_insert_with(Vec::new)
-            .push(varmer_ref.clone());
-        prefixes
-            .entry(prefix)
-            .or_insert_with(Vec::new)
-            .push(varmer_ref.clone());
-    }
-
-    for (prefix, out_varmers) in prefixes.iter() {
-        if let Some(in_varmers) = suffixes.get(prefix) {
-            for in_varmer in in_varmers.iter() {
-                for out_varmer in out_varmers.iter() {
-                    if let Some(in_info) = dbg.get_mut(&**in_varmer) {
-                        in_info.out_varmers.push(Arc::clone(out_varmer));
-                    }
-                    if let Some(out_info) = dbg.get_mut(&**out_varmer) {
-                        out_info.in_varmers.push(Arc::clone(in_varmer));
-                    }
-                }
-            }
-        }
+//Graph of the distinct varmers of nodes, keeping the first coverage of each varmer. Nodes are
+//laid out in the iteration order of a hash map of the varmers; later stages break ties by node
+//order.
+fn distinct_varmer_graph(nodes: &[(&[(SnpPosition, Genotype)], u64)]) -> VarmerGraph {
+    let mut distinct = FxHashMap::default();
+    for (varmer, _, first) in count_in_order(nodes, |node, varmers| varmers.push(node.0)) {
+        distinct.insert(varmer, nodes[first].1);
     }
+    VarmerGraph::from_nodes(distinct)
 }
 
-pub fn print_dbg(dbg: &FxHashMap<VarMer, DBGInfo>, file_name: &str) {
+pub fn print_dbg(dbg: &VarmerGraph, file_name: &str) {
+    let label = |id: NodeId| {
+        let node = dbg.node(id);
+        let mut s = node
+            .iter()
+            .map(|(_, geno)| format!("{}", geno))
+            .collect::<Vec<String>>()
+            .join("");
+        //append coverage
+        s.push_str(&format!(
+            "_{}-{}_COV:{}_LEN:{}",
+            node.first().unwrap().0,
+            node.last().unwrap().0,
+            dbg.coverage[id as usize],
+            node.len()
+        ));
+        s
+    };
     let mut dot = String::from("digraph G {\n");
-    for (node, info) in dbg.iter() {
-        let out_edges = &info.out_varmers;
-        if out_edges.len() == 0 && info.in_varmers.len() == 0 {
-            let mut s1 = node
-                .iter()
-                .map(|(_, geno)| format!("{}", geno))
-                .collect::<Vec<String>>()
-                .join("");
-            s1.push_str(&format!(
-                "_{}-{}_COV:{}_LEN:{}",
-                node.first().unwrap().0,
-                node.last().unwrap().0,
-                info.coverage,
-                node.len()
-            ));
+    for node in dbg.ids() {
+        let out_edges = &dbg.out_edges[node as usize];
+        if out_edges.is_empty() && dbg.in_edges[node as usize].is_empty() {
+            let s1 = label(node);
             dot.push_str(&format!("    \"{}\" -> \"{}\";\n", s1, s1));
         }
         for out in out_edges {
-            let mut s1 = node
-                .iter()
-                .map(|(_, geno)| format!("{}", geno))
-                .collect::<Vec<String>>()
-                .join("");
-            let mut s2 = out
-                .iter()
-                .map(|(_, geno)| format!("{}", geno))
-                .collect::<Vec<String>>()
-                .join("");
-            //append coverage to s1 and s2
-            s1.push_str(&format!(
-                "_{}-{}_COV:{}_LEN:{}",
-                node.first().unwrap().0,
-                node.last().unwrap().0,
-                info.coverage,
-                node.len()
-            ));
-            s2.push_str(&format!(
-                "_{}-{}_COV:{}_LEN:{}",
-                out.first().unwrap().0,
-                out.last().unwrap().0,
-                dbg.get(&**out).unwrap().coverage,
-                out.len()
-            ));
-            dot.push_str(&format!("    \"{}\" -> \"{}\";\n", s1, s2));
+            dot.push_str(&format!("    \"{}\" -> \"{}\";\n", label(node), label(*out)));
         }
     }
     dot.push_str("}\n");
@@ -785,139 +861,346 @@ pub fn print_dbg(dbg: &FxHashMap<VarMer, DBGInfo>, file_name: &str) {
     std::fs::write(file_name, dot).expect("Unable to write file");
 }
 
-fn dbg_from_frags(
-    dbg_frags: &Vec<FragDBG>,
-    k: usize,
-    prev_dbg: Option<FxHashMap<VarMer, DBGInfo>>,
-    unitigs: Option<&FxHashMap<VarMer, DBGInfo>>,
-    base_k: Option<usize>,
-) -> FxHashMap<VarMer, DBGInfo> {
-    // DBG Format: key -> edges
-    let mut dbg: FxHashMap<VarMer, DBGInfo> = FxHashMap::default();
-    let mut sequences = vec![];
+//Write a varmer graph as GFA1. Segments have no sequence; LN is the number of SNPs, DP the
+//coverage, and the genotypes, SNP range and 1-based genomic range are in the gt, sr and gr tags.
+//Links overlap by the SNPs shared between the end of one varmer and the start of the next.
+//Each path is written as a P-line; a path with consecutive unitigs that are not linked is split
+//there into P-lines named <name>.1, <name>.2, etc.
+pub fn print_gfa(
+    graph: &VarmerGraph,
+    snp_pos_to_genome_pos: &[usize],
+    paths: &[(String, Vec<NodeId>)],
+    file_name: &str,
+) {
+    let nodes = graph.sorted_ids();
+    let mut node_ids = vec![0; graph.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        node_ids[*node as usize] = i + 1;
+    }
+    let genome_pos = |snp_pos: u32| snp_pos_to_genome_pos[snp_pos as usize - 1] + 1;
+
+    let mut gfa = BufWriter::new(std::fs::File::create(file_name).expect("Unable to write file"));
+    writeln!(gfa, "H\tVN:Z:1.0").unwrap();
+    for node in nodes.iter() {
+        let varmer = graph.node(*node);
+        let genotypes = varmer
+            .iter()
+            .map(|(_, geno)| format!("{}", geno))
+            .collect::<Vec<String>>()
+            .join("");
+        let first = varmer.first().unwrap().0;
+        let last = varmer.last().unwrap().0;
+        writeln!(
+            gfa,
+            "S\t{}\t*\tLN:i:{}\tDP:f:{}\tgt:Z:{}\tsr:Z:{}-{}\tgr:Z:{}-{}",
+            node_ids[*node as usize],
+            varmer.len(),
+            graph.coverage[*node as usize],
+            genotypes,
+            first,
+            last,
+            genome_pos(first),
+            genome_pos(last)
+        )
+        .unwrap();
+    }
+    for node in nodes.iter() {
+        let mut outs = graph.out_edges[*node as usize]
+            .iter()
+            .map(|out| (node_ids[*out as usize], *out))
+            .collect::<Vec<_>>();
+        outs.sort();
+        for (out_id, out) in outs {
+            writeln!(
+                gfa,
+                "L\t{}\t+\t{}\t+\t{}",
+                node_ids[*node as usize],
+                out_id,
+                gfa_overlap(graph.node(*node), graph.node(out))
+            )
+            .unwrap();
+        }
+    }
+    for (name, path) in paths.iter() {
+        let mut pieces: Vec<Vec<String>> = vec![];
+        for (i, node) in path.iter().enumerate() {
+            if i == 0 || !graph.out_edges[path[i - 1] as usize].contains(node) {
+                pieces.push(vec![]);
+            }
+            pieces.last_mut().unwrap().push(format!("{}+", node_ids[*node as usize]));
+        }
+        if pieces.len() == 1 {
+            writeln!(gfa, "P\t{}\t{}\t*", name, pieces[0].join(",")).unwrap();
+        } else {
+            for (i, piece) in pieces.iter().enumerate() {
+                writeln!(gfa, "P\t{}.{}\t{}\t*", name, i + 1, piece.join(",")).unwrap();
+            }
+        }
+    }
+}
 
-    if let Some(unitigs) = unitigs.as_ref() {
-        for (unitig, _) in unitigs.iter() {
-            sequences.push(unitig);
+//Overlap of two linked varmers as a GFA CIGAR, counting shared SNPs.
+fn gfa_overlap(varmer1: &[(SnpPosition, Genotype)], varmer2: &[(SnpPosition, Genotype)]) -> String {
+    for len in (1..varmer1.len().min(varmer2.len()) + 1).rev() {
+        if varmer1[varmer1.len() - len..] == varmer2[..len] {
+            return format!("{}M", len);
         }
     }
-    for frag in dbg_frags.iter() {
-        sequences.push(&frag.seq);
-    }
-
-    for seq in sequences.iter() {
-        let mut varmer = VecDeque::new();
-        for (curr_pos, curr_geno) in seq.into_iter() {
-            varmer.push_back((*curr_pos, *curr_geno));
-            if varmer.len() == k {
-                let new_varmer = varmer.iter().cloned().collect::<VarMer>();
-                if let Some(prev_dbg) = prev_dbg.as_ref() {
-                    let base_k = base_k.unwrap();
-                    let prev_kmer = &new_varmer[0..k - base_k];
-                    let end_base_kmer = &new_varmer[base_k..];
-                    let min_cov;
-                    if let Some(prev_info) = prev_dbg.get(prev_kmer) {
-                        if let Some(end_cov) = prev_dbg.get(end_base_kmer) {
-                            min_cov = prev_info.coverage.min(end_cov.coverage);
-                        } else {
-                            min_cov = 0;
-                        }
-                    } else {
-                        min_cov = 0;
-                    }
+    String::from("*")
+}
 
-                    if min_cov != 0 {
-                        dbg.entry(new_varmer).or_insert(DBGInfo {
-                            out_varmers: vec![],
-                            in_varmers: vec![],
-                            coverage: min_cov,
-                        });
-                    }
-                } else {
-                    let info = dbg.entry(new_varmer).or_insert(DBGInfo {
-                        out_varmers: vec![],
-                        in_varmers: vec![],
-                        coverage: 0,
-                    });
-                    info.coverage += 1;
+//Unitigs of graph consistent with a haplotype, as a walk ordered by position. Unitigs
+//contained in the span of an earlier one are skipped.
+fn thread_haplotype(graph: &VarmerGraph, hap: &DictFrag) -> Vec<NodeId> {
+    let mut consistent = graph
+        .ids()
+        .filter(|id| {
+            let varmer = graph.node(*id);
+            varmer.iter().any(|(pos, _)| hap.seq.contains_key(pos))
+                && varmer
+                    .iter()
+                    .all(|(pos, geno)| hap.seq.get(pos).is_none_or(|x| x == geno))
+        })
+        .collect::<Vec<_>>();
+    let span = |id: NodeId| {
+        let varmer = graph.node(id);
+        (varmer.first().unwrap().0, varmer.last().unwrap().0)
+    };
+    consistent.sort_by(|a, b| (span(*a).0, span(*b).1).cmp(&(span(*b).0, span(*a).1)));
+    let mut walk: Vec<NodeId> = vec![];
+    for id in consistent {
+        if walk.last().is_none_or(|x| span(id).1 > span(*x).1) {
+            walk.push(id);
+        }
+    }
+    walk
+}
+
+//The assembly graph's nodes are paths of unitigs (by index into unitigs). Turn each into the
+//varmer spelled by its unitigs so the graph can be written with SNP coordinates.
+fn integer_graph_to_varmers(assembly_graph: &VarmerGraph, unitigs: &[DictFrag]) -> VarmerGraph {
+    let to_varmer = |int_node: &[(SnpPosition, Genotype)]| {
+        let mut varmer: VarMer = vec![];
+        for (index, _) in int_node.iter() {
+            for pos_geno in unitigs[*index as usize].seq_vec.iter() {
+                if !varmer.contains(pos_geno) {
+                    varmer.push(*pos_geno);
                 }
-                varmer.pop_front();
             }
         }
+        varmer.sort();
+        varmer
+    };
+    let varmers = assembly_graph
+        .ids()
+        .map(|id| to_varmer(assembly_graph.node(id)))
+        .collect::<Vec<_>>();
+    let mut graph = VarmerGraph::from_nodes(
+        varmers
+            .iter()
+            .zip(assembly_graph.coverage.iter())
+            .map(|(varmer, cov)| (varmer.as_slice(), *cov)),
+    );
+    graph.out_edges = assembly_graph.out_edges.clone();
+    graph.in_edges = assembly_graph.in_edges.clone();
+    graph
+}
+
+fn dbg_from_frags(
+    dbg_frags: &Vec<FragDBG>,
+    k: usize,
+    prev_dbg: Option<&VarmerGraph>,
+    unitigs: Option<&VarmerGraph>,
+    base_k: Option<usize>,
+    gapped: bool,
+) -> VarmerGraph {
+    let counts;
+    let read_kmers;
+    let nodes;
+
+    if let Some(prev_dbg) = prev_dbg {
+        let base_k = base_k.unwrap();
+        let mut sequences: Vec<&[(SnpPosition, Genotype)]> = vec![];
+        if let Some(unitigs) = unitigs {
+            for unitig in unitigs.ids() {
+                sequences.push(unitigs.node(unitig));
+            }
+        }
+        if gapped {
+            //Varmers of the reads as in count_read_varmers, so none joins the SNPs on either side
+            //of a missing site. Coverages come from prev_dbg, so a varmer with a gap would at best
+            //resolve to a gap-free varmer that is already a node; these are dropped.
+            let sites = read_sites(dbg_frags);
+            read_kmers = dbg_frags
+                .par_iter()
+                .flat_map_iter(|frag| {
+                    let mut varmers = vec![];
+                    read_varmers(frag, k, &sites, &mut varmers);
+                    varmers.into_iter().filter(|x| x.iter().all(|(_, geno)| *geno != GAP_CHAR))
+                })
+                .collect::<Vec<VarMer>>();
+            sequences.extend(read_kmers.iter().map(|x| x.as_slice()));
+        } else {
+            for frag in dbg_frags.iter() {
+                sequences.push(&frag.seq);
+            }
+        }
+        nodes = sequences
+            .par_iter()
+            .flat_map_iter(|seq| {
+                seq.windows(k).filter_map(|new_varmer| {
+                    let prev_kmer = prev_dbg.find(&new_varmer[0..k - base_k]);
+                    let end_base_kmer = prev_dbg.find(&new_varmer[base_k..]);
+                    let min_cov = match (prev_kmer, end_base_kmer) {
+                        (Some(prev), Some(end)) => {
+                            prev_dbg.coverage[prev as usize].min(prev_dbg.coverage[end as usize])
+                        }
+                        _ => 0,
+                    };
+                    (min_cov != 0).then_some((new_varmer, min_cov))
+                })
+            })
+            .collect::<Vec<_>>();
+    } else {
+        counts = count_read_varmers(dbg_frags, k, gapped);
+        nodes = counts
+            .iter()
+            .map(|(varmer, count)| (varmer.as_slice(), *count))
+            .collect::<Vec<_>>();
     }
 
-    get_edges_varmers(&mut dbg, k);
-    return dbg;
+    let mut dbg = distinct_varmer_graph(&nodes);
+    dbg.link_overlaps(k);
+    dbg
+}
+
+//Grow k from k towards max_k, building each graph from the previous graph and its unitigs.
+//Stops once the number of unitigs changes by < MULTI_K_STABLE_FRACTION, and keeps the previous
+//k if the next one gives more source unitigs, i.e. the graph starts to fragment. Returns the
+//unitigs of the final graph and the increase in k.
+fn grow_k(
+    dbg_frags: &Vec<FragDBG>,
+    mut dbg: VarmerGraph,
+    mut uni: VarmerGraph,
+    k: usize,
+    max_k: usize,
+    gapped: bool,
+) -> (VarmerGraph, usize) {
+    if max_k <= k || uni.is_empty() {
+        return (uni, 0);
+    }
+    let step = ((max_k - k) / constants::MULTI_K_ROUNDS).max(1);
+    let num_sources =
+        |graph: &VarmerGraph| graph.in_edges.iter().filter(|x| x.is_empty()).count();
+
+    let mut end = 0;
+    while k + end + step <= max_k {
+        let next_k = k + end + step;
+        let next_dbg = dbg_from_frags(dbg_frags, next_k, Some(&dbg), Some(&uni), Some(step), gapped);
+        let next_uni = get_unitigs(&next_dbg, next_k, false);
+        log::debug!("k = {}: {} unitigs", next_k, next_uni.len());
+        if next_uni.is_empty() || num_sources(&next_uni) > num_sources(&uni) {
+            log::debug!("Graph fragments at k = {}", next_k);
+            break;
+        }
+        let change = (next_uni.len() as f64 - uni.len() as f64).abs() / uni.len() as f64;
+        dbg = next_dbg;
+        uni = next_uni;
+        end += step;
+        if change < constants::MULTI_K_STABLE_FRACTION {
+            break;
+        }
+    }
+    (uni, end)
 }
 
 fn filter_dbg(
-    dbg: FxHashMap<VarMer, DBGInfo>,
+    dbg: &VarmerGraph,
     min_cov: Option<u64>,
-    bad_nodes: Option<Vec<VarMer>>,
+    bad_nodes: Option<Vec<NodeId>>,
     k: usize,
-    only_disconnected_small: bool,
-    num_snps : usize,
-) -> FxHashMap<VarMer, DBGInfo> {
-    let mut new_dbg = FxHashMap::default();
-    for (node, info) in dbg.iter() {
-        if let Some(bad_nodes) = bad_nodes.as_ref() {
-            if bad_nodes.contains(node) {
-                continue;
-            }
+) -> VarmerGraph {
+    let mut bad = vec![false; dbg.len()];
+    for node in bad_nodes.unwrap_or_default() {
+        bad[node as usize] = true;
+    }
+    let mut nodes = vec![];
+    for node in dbg.ids() {
+        let n = node as usize;
+        if bad[n] {
+            continue;
         }
         if let Some(min_cov) = min_cov {
-            if only_disconnected_small{
-                if info.coverage < min_cov && info.in_varmers.len() == 0 && info.out_varmers.len() == 0 && node.len() < num_snps / 2{
-                    continue;
-                }
-                
-            }
-            else if info.coverage <= min_cov {
+            if dbg.coverage[n] <= min_cov {
                 continue;
             }
         }
-        new_dbg.insert(
-            node.clone(),
-            DBGInfo {
-                out_varmers: vec![],
-                in_varmers: vec![],
-                coverage: info.coverage,
-            },
-        );
+        nodes.push((dbg.node(node), dbg.coverage[n]));
     }
-    get_edges_varmers(&mut new_dbg, k);
+    let mut new_dbg = distinct_varmer_graph(&nodes);
+    new_dbg.link_overlaps(k);
     return new_dbg;
 }
 
+//Unitigs without edges that are shorter than half of the SNPs and have coverage < min_cov.
+fn small_disconnected_unitigs(
+    unitigs: &VarmerGraph,
+    min_cov: u64,
+    num_snps: usize,
+) -> Vec<(NodeId, UnitigRemoval)> {
+    unitigs
+        .ids()
+        .filter(|node| {
+            let n = *node as usize;
+            unitigs.coverage[n] < min_cov
+                && unitigs.in_edges[n].is_empty()
+                && unitigs.out_edges[n].is_empty()
+                && unitigs.node(*node).len() < num_snps / 2
+        })
+        .map(|node| {
+            (node, UnitigRemoval {
+                step: String::from("small_disconnected"),
+                varmer: unitigs.node(node).to_vec(),
+                coverage: unitigs.coverage[node as usize],
+                test_coverage: min_cov,
+                error_rate: None,
+                p_value: None,
+            })
+        })
+        .collect()
+}
+
 fn get_unitigs(
-    dbg: &FxHashMap<VarMer, DBGInfo>,
+    dbg: &VarmerGraph,
     k: usize,
     unitig_graph: bool,
-) -> FxHashMap<VarMer, DBGInfo> {
-    let mut unitigs = FxHashMap::default();
-    let mut visited_nodes = FxHashSet::default();
-    for (node, info) in dbg.iter() {
-        if visited_nodes.contains(node) {
+) -> VarmerGraph {
+    let mut unitig_seqs = vec![];
+    let mut visited_nodes = vec![false; dbg.len()];
+    for node in dbg.ids() {
+        let n = node as usize;
+        if visited_nodes[n] {
             continue;
         }
-        if info.in_varmers.len() != 1
-            || (info.in_varmers.len() == 1
-                && dbg.get(&**info.in_varmers[0]).unwrap().out_varmers.len() != 1)
+        let in_varmers = &dbg.in_edges[n];
+        if in_varmers.len() != 1
+            || (in_varmers.len() == 1
+                && dbg.out_edges[in_varmers[0] as usize].len() != 1)
         {
-            let mut cov = info.coverage;
+            let mut cov = dbg.coverage[n];
             let mut count = 1;
-            visited_nodes.insert(node);
-            let mut unitig = node.clone();
-
-            if info.out_varmers.len() == 1 {
-                let mut prev = &node.clone();
-                let mut next = &info.out_varmers[0];
-                while dbg.get(&**prev).unwrap().out_varmers.len() == 1
-                    && dbg.get(&**next).unwrap().in_varmers.len() == 1
+            visited_nodes[n] = true;
+            let mut unitig = dbg.node(node).to_vec();
+
+            if dbg.out_edges[n].len() == 1 {
+                let mut prev = node;
+                let mut next = dbg.out_edges[n][0];
+                while dbg.out_edges[prev as usize].len() == 1
+                    && dbg.in_edges[next as usize].len() == 1
                 {
-                    visited_nodes.insert(next);
+                    visited_nodes[next as usize] = true;
                     if unitig_graph {
-                        for pg in next.iter() {
+                        for pg in dbg.node(next).iter() {
                             if unitig.contains(pg) {
                                 continue;
                             } else {
@@ -925,84 +1208,238 @@ fn get_unitigs(
                             }
                         }
                     } else {
-                        unitig.push(next.last().unwrap().clone());
+                        unitig.push(*dbg.node(next).last().unwrap());
                     }
 
-                    cov += dbg.get(&**next).unwrap().coverage;
+                    cov += dbg.coverage[next as usize];
                     count += 1;
 
-                    if dbg.get(&**next).unwrap().out_varmers.len() == 0 {
+                    if dbg.out_edges[next as usize].is_empty() {
                         break;
                     }
                     prev = next;
-                    next = &dbg.get(&**next).unwrap().out_varmers[0];
+                    next = dbg.out_edges[next as usize][0];
                 }
             }
-            unitigs.insert(
-                unitig,
-                DBGInfo {
-                    out_varmers: vec![],
-                    in_varmers: vec![],
-                    coverage: cov / count,
-                },
-            );
+            unitig_seqs.push((unitig, cov / count));
         }
     }
-    get_edges_varmers(&mut unitigs, k);
+    let unitig_nodes = unitig_seqs
+        .iter()
+        .map(|(unitig, cov)| (unitig.as_slice(), *cov))
+        .collect::<Vec<_>>();
+    let mut unitigs = distinct_varmer_graph(&unitig_nodes);
+    unitigs.link_overlaps(k);
     return unitigs;
 }
 
-fn count_kmers(dbg_frags: &Vec<FragDBG>, k: usize) -> (FxHashMap<VarMer, u64>, FxHashSet<u32>) {
-    let mut snps = FxHashSet::default();
+fn count_kmers(dbg_frags: &[FragDBG], k: usize, gapped: bool) -> (FxHashMap<VarMer, u64>, FxHashSet<u32>) {
+    let snps = dbg_frags
+        .par_iter()
+        .flat_map_iter(|frag| frag.seq.iter().map(|(curr_pos, _)| *curr_pos))
+        .collect::<FxHashSet<u32>>();
+    let kmers = count_read_varmers(dbg_frags, k, gapped);
+    (kmers, snps)
+}
+
+//Number of occurrences of each key and its first occurrence as (item, position in item).
+type KeyCounts<K> = FxHashMap<K, (u64, (usize, usize))>;
+
+//Distinct keys produced from items, in the order they first occur, with their number of
+//occurrences and the index of the first item they occur in. Chunks of items are counted in
+//parallel into maps sharded by hash, and the shards are merged in parallel, so the result does
+//not depend on the number of threads.
+fn count_in_order<'a, T: Sync, K: Hash + Eq + Send>(
+    items: &'a [T],
+    keys_of: impl Fn(&'a T, &mut Vec<K>) + Sync,
+) -> Vec<(K, u64, usize)> {
+    let num_shards = constants::VARMER_COUNT_SHARDS;
+    let chunk_size = items.len().div_ceil(rayon::current_num_threads() * 4).max(1);
+    let chunk_shards = items
+        .par_chunks(chunk_size)
+        .enumerate()
+        .map(|(chunk, chunk_items)| {
+            let mut shards: Vec<KeyCounts<K>> =
+                (0..num_shards).map(|_| FxHashMap::default()).collect();
+            let mut keys = vec![];
+            for (i, item) in chunk_items.iter().enumerate() {
+                keys_of(item, &mut keys);
+                for (j, key) in keys.drain(..).enumerate() {
+                    let shard = fxhash::hash(&key) % num_shards;
+                    shards[shard]
+                        .entry(key)
+                        .or_insert((0, (chunk * chunk_size + i, j)))
+                        .0 += 1;
+                }
+            }
+            shards
+        })
+        .collect::<Vec<_>>();
+
+    let mut by_shard = (0..num_shards).map(|_| vec![]).collect::<Vec<_>>();
+    for shards in chunk_shards {
+        for (shard, counts) in shards.into_iter().enumerate() {
+            by_shard[shard].push(counts);
+        }
+    }
+    let mut counts = by_shard
+        .into_par_iter()
+        .flat_map_iter(|chunk_counts| {
+            let mut merged: KeyCounts<K> = FxHashMap::default();
+            for counts in chunk_counts {
+                for (key, (count, first)) in counts {
+                    let entry = merged.entry(key).or_insert((0, first));
+                    entry.0 += count;
+                    entry.1 = entry.1.min(first);
+                }
+            }
+            merged.into_iter().map(|(key, (count, first))| (first, key, count))
+        })
+        .collect::<Vec<_>>();
+    counts.par_sort_unstable_by_key(|x| x.0);
+    counts
+        .into_iter()
+        .map(|(first, key, count)| (key, count, first.0))
+        .collect()
+}
+
+//Count the varmers (k consecutive SNPs) in reads. With gapped, a site inside a read that the
+//read has no allele for (deletion, low quality base, allele not in the VCF) is kept as
+//GAP_CHAR instead of joining the SNPs on either side of it. A varmer with one gap is counted
+//towards the gap-free varmer it matches, if exactly one does; others are dropped. The map is
+//filled in the order varmers first occur in the reads. Its iteration order (and so the node
+//order of graphs built from it) is not that order, but a hash map's layout depends on the order
+//its keys are inserted in, so a fixed insertion order keeps the node order the same for any
+//number of threads.
+fn count_read_varmers(dbg_frags: &[FragDBG], k: usize, gapped: bool) -> FxHashMap<VarMer, u64> {
+    let sites = if gapped { read_sites(dbg_frags) } else { vec![] };
+
     let mut kmers = FxHashMap::default();
-    for frag in dbg_frags.iter() {
-        let seq = &frag.seq;
-        let mut varmer = VecDeque::new();
-        for (curr_pos, curr_geno) in seq.iter() {
-            snps.insert(*curr_pos);
-            varmer.push_back((*curr_pos, *curr_geno));
-            if varmer.len() == k {
-                let new_varmer = varmer.iter().cloned().collect::<VarMer>();
-                *kmers.entry(new_varmer).or_insert(0) += 1;
-                varmer.pop_front();
+    let mut gapped_kmers = vec![];
+    let varmer_counts = count_in_order(dbg_frags, |frag, varmers| {
+        read_varmers(frag, k, &sites, varmers)
+    });
+    for (varmer, count, _) in varmer_counts {
+        if varmer.iter().any(|(_, geno)| *geno == GAP_CHAR) {
+            gapped_kmers.push((varmer, count));
+        } else {
+            kmers.insert(varmer, count);
+        }
+    }
+    if gapped_kmers.is_empty() {
+        return kmers;
+    }
+
+    //Gap-free varmers by their sequence with one site masked, None if several match.
+    let queries = gapped_kmers.iter().map(|x| &x.0).collect::<FxHashSet<&VarMer>>();
+    let mut masked_to_varmer: FxHashMap<VarMer, Option<VarMer>> = FxHashMap::default();
+    for varmer in kmers.keys() {
+        for i in 0..varmer.len() {
+            let mut masked = varmer.clone();
+            masked[i].1 = GAP_CHAR;
+            if queries.contains(&masked) {
+                masked_to_varmer
+                    .entry(masked)
+                    .and_modify(|x| *x = None)
+                    .or_insert_with(|| Some(varmer.clone()));
+            }
+        }
+    }
+    let mut num_resolved = 0;
+    let mut num_gapped = 0;
+    for (gapped_kmer, count) in gapped_kmers.iter() {
+        if let Some(Some(varmer)) = masked_to_varmer.get(gapped_kmer) {
+            *kmers.get_mut(varmer).unwrap() += count;
+            num_resolved += count;
+        }
+        num_gapped += count;
+    }
+    log::trace!("{} of {} gapped varmers matched a varmer", num_resolved, num_gapped);
+    kmers
+}
+
+//Sorted sites that any read has an allele for.
+fn read_sites(dbg_frags: &[FragDBG]) -> Vec<u32> {
+    let mut sites = dbg_frags
+        .par_iter()
+        .flat_map_iter(|frag| frag.seq.iter().map(|(pos, _)| *pos))
+        .collect::<FxHashSet<u32>>()
+        .into_iter()
+        .collect::<Vec<u32>>();
+    sites.sort();
+    sites
+}
+
+//Varmers of a read with at most one gap. With sites (the sorted sites of all reads), sites
+//inside the read that it has no allele for are filled with GAP_CHAR first.
+fn read_varmers(frag: &FragDBG, k: usize, sites: &[u32], varmers: &mut Vec<VarMer>) {
+    let mut seq = Cow::Borrowed(&frag.seq);
+    if !sites.is_empty() && !frag.seq.is_empty() {
+        let first = sites.binary_search(&frag.seq.first().unwrap().0).unwrap();
+        let last = sites.binary_search(&frag.seq.last().unwrap().0).unwrap();
+        if last - first + 1 > frag.seq.len() {
+            let mut filled = Vec::with_capacity(last - first + 1);
+            let mut i = 0;
+            for pos in sites[first..last + 1].iter() {
+                if frag.seq[i].0 == *pos {
+                    filled.push(frag.seq[i]);
+                    i += 1;
+                } else {
+                    filled.push((*pos, GAP_CHAR));
+                }
+            }
+            seq = Cow::Owned(filled);
+        }
+    }
+
+    let mut varmer = VecDeque::new();
+    let mut gaps = 0;
+    for (curr_pos, curr_geno) in seq.iter() {
+        varmer.push_back((*curr_pos, *curr_geno));
+        if *curr_geno == GAP_CHAR {
+            gaps += 1;
+        }
+        if varmer.len() == k {
+            if gaps <= 1 {
+                varmers.push(varmer.iter().cloned().collect::<VarMer>());
+            }
+            if varmer.pop_front().unwrap().1 == GAP_CHAR {
+                gaps -= 1;
             }
         }
     }
-    return (kmers, snps);
 }
 
 pub fn get_paths(
-    unitigs: &FxHashMap<VarMer, DBGInfo>,
+    unitigs: &VarmerGraph,
     conservative: bool,
-) -> Vec<Vec<(&VarMer, usize)>> {
+) -> Vec<Vec<(NodeId, usize)>> {
     let mut visited_nodes = FxHashSet::default();
     let mut paths = vec![];
     let mut sinks_and_forks = vec![];
-    for (node, info) in unitigs.iter() {
-        if info.in_varmers.len() == 0 {
-            sinks_and_forks.push((node, info));
-        } else if info.out_varmers.len() > 1 {
-            sinks_and_forks.push((node, info));
+    for node in unitigs.ids() {
+        if unitigs.in_edges[node as usize].is_empty() || unitigs.out_edges[node as usize].len() > 1 {
+            sinks_and_forks.push(node);
         }
     }
-    sinks_and_forks.sort_by(|a, b| b.1.coverage.cmp(&a.1.coverage));
-    for (node, info) in sinks_and_forks.iter() {
-        if info.out_varmers.len() == 0 {
-            paths.push(vec![(*node, info.coverage as usize)]);
+    sinks_and_forks.sort_by(|a, b| unitigs.coverage[*b as usize].cmp(&unitigs.coverage[*a as usize]));
+    for node in sinks_and_forks.iter() {
+        let cov = unitigs.coverage[*node as usize] as usize;
+        if unitigs.out_edges[*node as usize].is_empty() {
+            paths.push(vec![(*node, cov)]);
         }
-        for out in info.out_varmers.iter() {
-            let mut path = vec![(*node, info.coverage as usize)];
-            let mut curr_node = &**out;
+        for out in unitigs.out_edges[*node as usize].iter() {
+            let mut path = vec![(*node, cov)];
+            let mut curr_node = *out;
             loop {
-                if visited_nodes.contains(curr_node) && conservative {
+                if visited_nodes.contains(&curr_node) && conservative {
                     break;
                 }
-                visited_nodes.insert(curr_node.clone());
-                let info = unitigs.get(&**curr_node).unwrap();
-                path.push((curr_node, info.coverage as usize));
-                let num_in_edges = info.in_varmers.len();
+                visited_nodes.insert(curr_node);
+                path.push((curr_node, unitigs.coverage[curr_node as usize] as usize));
+                let num_in_edges = unitigs.in_edges[curr_node as usize].len();
                 if num_in_edges == 1 {
-                    curr_node = &unitigs.get(&**curr_node).unwrap().out_varmers[0];
+                    curr_node = unitigs.out_edges[curr_node as usize][0];
                 } else {
                     paths.push(path);
                     break;
@@ -1013,75 +1450,121 @@ pub fn get_paths(
     return paths;
 }
 
+//How a site of a varmer compares with the same site of a varmer it is aligned to.
+enum SiteMatch {
+    Same,
+    RefToAlt,
+    AltToRef,
+    Del,
+}
+
+//Compare the alleles of two varmers site by site. Sites in both are the same or a mismatch, and
+//sites in only one of them, within the range of the other, are deletions. Both seq_vecs are
+//ordered by position, so this is a merge of the two.
+fn compare_alleles(varmer_d: &DictFrag, ref_varmer: &DictFrag, mut record: impl FnMut(u32, SiteMatch)) {
+    let query = &varmer_d.seq_vec;
+    let reference = &ref_varmer.seq_vec;
+    let mut i = 0;
+    let mut j = 0;
+    while i < query.len() || j < reference.len() {
+        if j == reference.len() || (i < query.len() && query[i].0 < reference[j].0) {
+            let pos = query[i].0;
+            if pos <= ref_varmer.last_position && pos >= ref_varmer.first_position {
+                record(pos, SiteMatch::Del);
+            }
+            i += 1;
+        } else if i == query.len() || reference[j].0 < query[i].0 {
+            let pos = reference[j].0;
+            if pos <= varmer_d.last_position && pos >= varmer_d.first_position {
+                record(pos, SiteMatch::Del);
+            }
+            j += 1;
+        } else {
+            let gn1 = query[i].1;
+            let gn2 = reference[j].1;
+            if gn1 == gn2 {
+                record(query[i].0, SiteMatch::Same);
+            } else if gn1 == 0 && gn2 != 0 {
+                record(query[i].0, SiteMatch::RefToAlt);
+            } else {
+                record(query[i].0, SiteMatch::AltToRef);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+}
+
+//Interval index of dict_frags by SNP range, for get_hits.
+fn dict_frag_index(dict_frags: &[DictFrag]) -> Lapper<u32, usize> {
+    let data = dict_frags
+        .iter()
+        .enumerate()
+        .map(|(i, x)| Interval {
+            start: x.first_position,
+            stop: x.last_position + 1,
+            val: i,
+        })
+        .collect::<Vec<Interval<u32, usize>>>();
+    Lapper::new(data)
+}
+
 fn get_hits<'a>(
     varmer_d: &'a DictFrag,
-    dict_frags: &'a Vec<DictFrag>,
+    dict_frags: &'a [DictFrag],
+    index: &Lapper<u32, usize>,
     hit_threshold: usize,
     penalty_allow: usize,
     allow_equal: bool,
 ) -> Vec<Hit<'a>> {
     let mut hits = vec![];
-    for ref_varmer in dict_frags.iter() {
+    //dict_frags whose SNP range overlaps varmer_d, in order
+    let mut overlapping = index
+        .find(varmer_d.first_position, varmer_d.last_position + 1)
+        .map(|x| x.val)
+        .collect::<Vec<usize>>();
+    overlapping.sort_unstable();
+    for ref_varmer in overlapping.into_iter().map(|i| &dict_frags[i]) {
         if varmer_d == ref_varmer && !allow_equal {
             continue;
         }
 
-        let mut same = FxHashSet::default();
-        let mut r_to_a = FxHashSet::default();
-        let mut a_to_r = FxHashSet::default();
-        let mut del = FxHashSet::default();
-
-        //check if unitigs overlap based on first and last position
-        if (varmer_d.first_position <= ref_varmer.last_position
-            && varmer_d.last_position >= ref_varmer.first_position)
-            || (ref_varmer.first_position <= varmer_d.last_position
-                && ref_varmer.last_position >= varmer_d.first_position)
-        {
-            for (pos, geno) in ref_varmer.seq.iter() {
-                if varmer_d.seq.contains_key(pos) {
-                    let gn2 = *geno;
-                    let gn1 = *varmer_d.seq.get(pos).unwrap();
-                    if gn1 == gn2 {
-                        same.insert(*pos);
-                    } else if gn1 == 0 && gn2 != 0 {
-                        r_to_a.insert(*pos);
-                    } else if gn1 != 0 && gn2 == 0 {
-                        a_to_r.insert(*pos);
-                    } else {
-                        a_to_r.insert(*pos);
-                    }
-                } else if *pos <= varmer_d.last_position && *pos >= varmer_d.first_position {
-                    del.insert(*pos);
-                }
-            }
-
-            for (pos, _) in varmer_d.seq.iter() {
-                if !ref_varmer.seq.contains_key(pos)
-                    && *pos <= ref_varmer.last_position
-                    && *pos >= ref_varmer.first_position
-                {
-                    del.insert(*pos);
-                }
-            }
-            if r_to_a.len() + a_to_r.len() + del.len() <= hit_threshold {
-                if same.len() + penalty_allow > (r_to_a.len() + a_to_r.len()){
-                    hits.push(Hit {
-                        varmer: &ref_varmer,
-                        same,
-                        r_to_a,
-                        a_to_r,
-                        del,
-                    });
-                }
-            }
+        //Count first; only hits need the sites.
+        let mut num_same = 0;
+        let mut num_mismatch = 0;
+        let mut num_del = 0;
+        compare_alleles(varmer_d, ref_varmer, |_, site| match site {
+            SiteMatch::Same => num_same += 1,
+            SiteMatch::RefToAlt | SiteMatch::AltToRef => num_mismatch += 1,
+            SiteMatch::Del => num_del += 1,
+        });
+        if num_mismatch + num_del > hit_threshold || num_same + penalty_allow <= num_mismatch {
+            continue;
         }
+
+        let mut hit = Hit {
+            varmer: ref_varmer,
+            same: FxHashSet::default(),
+            r_to_a: FxHashSet::default(),
+            a_to_r: FxHashSet::default(),
+            del: FxHashSet::default(),
+        };
+        compare_alleles(varmer_d, ref_varmer, |pos, site| {
+            match site {
+                SiteMatch::Same => hit.same.insert(pos),
+                SiteMatch::RefToAlt => hit.r_to_a.insert(pos),
+                SiteMatch::AltToRef => hit.a_to_r.insert(pos),
+                SiteMatch::Del => hit.del.insert(pos),
+            };
+        });
+        hits.push(hit);
     }
 
     hits.sort_by(|a, b| {
         (a.varmer.first_position, a.varmer.last_position)
             .cmp(&(b.varmer.first_position, b.varmer.last_position))
     });
-    return hits;
+    hits
 }
 
 fn varmers_from_dp_res<'a>(dp_res: &'a DpResult, cutoff_ratio: f64) -> Vec<DictFrag> {
@@ -1105,7 +1588,7 @@ fn varmers_from_dp_res<'a>(dp_res: &'a DpResult, cutoff_ratio: f64) -> Vec<DictF
 fn dp_hits<'a>(
     hits: &'a Vec<Hit>,
     varmer_d: &DictFrag,
-    graph: &FxHashMap<VarMer, DBGInfo>,
+    graph: &VarmerGraph,
     threshold: usize,
     mismatch_pen: f64,
     ambiguous_allowed: bool,
@@ -1113,7 +1596,7 @@ fn dp_hits<'a>(
     require_dag: GraphConstraint,
     band: usize,
     k: usize,
-    forbidden_nodes: &FxHashSet<&VarMer>,
+    forbidden_nodes: &FxHashSet<NodeId>,
 ) -> DpResult<'a> {
     let empty_result = DpResult {
         score: (0., 0),
@@ -1171,9 +1654,14 @@ fn dp_hits<'a>(
         .map(|x| x.del.clone())
         .collect::<Vec<FxHashSet<u32>>>();
     let mut chain_length = vec![1; hits.len()];
+    //Hits are nodes of graph.
+    let hit_nodes = hits
+        .iter()
+        .map(|x| graph.find(&x.varmer.seq_vec).unwrap() as usize)
+        .collect::<Vec<usize>>();
 
     for i in 0..hits.len() {
-        if forbidden_nodes.contains(&hits[i].varmer.seq_vec) {
+        if forbidden_nodes.contains(&(hit_nodes[i] as NodeId)) {
             continue;
         }
         let mut best_index = i;
@@ -1199,35 +1687,25 @@ fn dp_hits<'a>(
             let mut score = 0;
             let mut bad = 0;
             let mut inside = false;
-            if forbidden_nodes.contains(&hits[j].varmer.seq_vec) {
+            if forbidden_nodes.contains(&(hit_nodes[j] as NodeId)) {
                 continue;
             }
             if require_dag == GraphConstraint::RequireDag{
-                for varmer in graph[&hits[i].varmer.seq_vec].in_varmers.iter() {
-                    if &**varmer == &hits[j].varmer.seq_vec {
-                        inside = true;
-                        break;
-                    }
-                }
+                inside = graph.in_edges[hit_nodes[i]].contains(&(hit_nodes[j] as NodeId));
             } else if require_dag == GraphConstraint::NoRequireDag {
                 if hits[j].varmer.last_position  < hits[i].varmer.first_position + k as u32{
                     inside = true;
                 }
             }
             else if require_dag == GraphConstraint::RequireDagRescue{
-                if graph[&hits[j].varmer.seq_vec].out_varmers.len() == 0 &&
-                 graph[&hits[i].varmer.seq_vec].in_varmers.len() == 0 {
+                if graph.out_edges[hit_nodes[j]].is_empty() &&
+                 graph.in_edges[hit_nodes[i]].is_empty() {
                     if hits[j].varmer.last_position < hits[i].varmer.first_position  + k as u32 {
                         inside = true;
                     }
                  }
                 else{
-                    for varmer in graph[&hits[i].varmer.seq_vec].in_varmers.iter() {
-                        if &**varmer == &hits[j].varmer.seq_vec {
-                            inside = true;
-                            break;
-                        }
-                    }
+                    inside = graph.in_edges[hit_nodes[i]].contains(&(hit_nodes[j] as NodeId));
                 }
             }
             else{
@@ -1359,7 +1837,7 @@ fn fmt_varmer_d(varmer_d: &DictFrag) -> String {
     seq1
 }
 
-fn print_varmer(varmer: &VarMer, trace: bool) {
+fn print_varmer(varmer: &[(SnpPosition, Genotype)], trace: bool) {
     let mut seq1 = varmer
         .iter()
         .map(|(_, geno)| format!("{}", geno))
@@ -1388,15 +1866,15 @@ fn print_varmer_d(varmer_d: &DictFrag, trace: bool) {
     }
 }
 
-pub fn query_unitigs(unitigs: &FxHashMap<VarMer, DBGInfo>, threshold: usize) -> Vec<VarMer> {
+pub fn query_unitigs(unitigs: &VarmerGraph, threshold: usize) -> Vec<(NodeId, UnitigRemoval)> {
     let mut dict_unitigs = vec![];
     let mut bad_unitigs = vec![];
-    for unitig in unitigs.iter() {
+    for node in unitigs.ids() {
         let mut dict_seq = FxHashMap::default();
-        let cov = unitig.1.coverage;
+        let cov = unitigs.coverage[node as usize];
         let mut first_position = u32::MAX;
         let mut last_position = 0;
-        for (pos, geno) in unitig.0.iter() {
+        for (pos, geno) in unitigs.node(node).iter() {
             dict_seq.insert(*pos, *geno);
             if *pos < first_position {
                 first_position = *pos;
@@ -1405,20 +1883,22 @@ pub fn query_unitigs(unitigs: &FxHashMap<VarMer, DBGInfo>, threshold: usize) ->
                 last_position = *pos;
             }
         }
-        dict_unitigs.push(DictFrag {
+        dict_unitigs.push((node, DictFrag {
             seq: dict_seq,
-            seq_vec: unitig.0.clone(),
+            seq_vec: unitigs.node(node).to_vec(),
             first_position,
             last_position,
             cov,
-        });
+        }));
     }
 
-    dict_unitigs.sort_by(|a, b| a.cov.cmp(&b.cov));  
+    dict_unitigs.sort_by_key(|a| a.1.cov);
+    let (unitig_nodes, dict_unitigs): (Vec<NodeId>, Vec<DictFrag>) = dict_unitigs.into_iter().unzip();
+    let unitig_index = dict_frag_index(&dict_unitigs);
 
     let mut failed_unitigs = FxHashSet::default();
-    for unitig1 in dict_unitigs.iter() {
-        let hits = get_hits(unitig1, &dict_unitigs, threshold, usize::MAX/2, false);
+    for (node, unitig1) in unitig_nodes.into_iter().zip(dict_unitigs.iter()) {
+        let hits = get_hits(unitig1, &dict_unitigs, &unitig_index, threshold, usize::MAX/2, false);
 
         log::trace!("QUERY");
         print_varmer_d(unitig1, true);
@@ -1509,35 +1989,32 @@ pub fn query_unitigs(unitigs: &FxHashMap<VarMer, DBGInfo>, threshold: usize) ->
         }
         log::trace!("QUERY UNITIGS: FINAL COV: {}, contig_COV {}", final_cov, unitig1.cov);
 
-        let mut failed = false;
         let num_errs = dp_res.total_errs as f64;
 
-        let mult;
-        if dp_res.rtoa_max.len() == 0 && dp_res.ator_max.len() == 0 {
-            mult = 0.35.powi(dp_res.dels_max.len() as i32);
-        } else {
-            mult = 0.15.powi(dp_res.rtoa_max.len() as i32)
-                * 0.10.powi(dp_res.ator_max.len() as i32);
-        }
+        let mult = error_rate(dp_res.rtoa_max.len(), dp_res.ator_max.len(), dp_res.dels_max.len());
 
         if num_errs > 0.{
-            if binomial_test(
+            let p_value = binomial_test(
                 final_cov as u64,
                 unitig1.cov as u64,
                 mult,
-            ) > 0.005
+            );
+            if p_value > 0.005
             {
-                failed = true;
                 log::trace!("BAD ERR: {}", final_cov);
                 print_varmer_d(unitig1, true);
                 print_varmer_d(hits[dp_res.max_index].varmer, true);
+                failed_unitigs.insert(node);
+                bad_unitigs.push((node, UnitigRemoval {
+                    step: format!("query_unitigs_{}", threshold),
+                    varmer: unitig1.seq_vec.clone(),
+                    coverage: unitig1.cov,
+                    test_coverage: final_cov,
+                    error_rate: Some(mult),
+                    p_value: Some(p_value),
+                }));
             }
         }
-
-        if failed {
-            failed_unitigs.insert(&unitig1.seq_vec);
-            bad_unitigs.push(unitig1.seq_vec.clone());
-        }
     }
     return bad_unitigs;
 }
@@ -1556,6 +2033,16 @@ fn fragdbg_to_dictfrag(frag: &FragDBG) -> DictFrag {
     }
 }
 
+//Chance that a unitig differing from another by these ref->alt, alt->ref and deleted sites
+//arises from errors in reads of the other.
+fn error_rate(rtoa: usize, ator: usize, dels: usize) -> f64 {
+    if rtoa == 0 && ator == 0 {
+        0.35.powi(dels as i32)
+    } else {
+        0.15.powi(rtoa as i32) * 0.10.powi(ator as i32)
+    }
+}
+
 fn binomial_test(n: u64, k: u64, p: f64) -> f64 {
     // n: number of trials
     // k: number of successes
@@ -1572,17 +2059,17 @@ fn binomial_test(n: u64, k: u64, p: f64) -> f64 {
 
 fn get_path_haps<'a>(
     dbg_frags: &'a Vec<FragDBG>,
-    final_unitigs: &FxHashMap<VarMer, DBGInfo>,
-    paths: Vec<Vec<(&VarMer, usize)>>,
+    final_unitigs: &VarmerGraph,
+    paths: Vec<Vec<(NodeId, usize)>>,
     _snps: usize,
     options: &Options,
 ) -> Vec<HapFinalResult<'a>> {
-    let mut path_frags: Vec<DictFrag> = vec![];
+    let mut path_frags: Vec<(DictFrag, Vec<NodeId>)> = vec![];
     for path in paths {
         let mut seq = vec![];
         let mut covs = vec![];
-        for (varmer, cov) in path.iter() {
-            for (pos, geno) in varmer.iter() {
+        for (node, cov) in path.iter() {
+            for (pos, geno) in final_unitigs.node(*node).iter() {
                 if !seq.contains(&(*pos, *geno)) {
                     seq.push((*pos, *geno));
                 }
@@ -1602,9 +2089,9 @@ fn get_path_haps<'a>(
             final_cov
         ));
         log::trace!("GETTING PATH HAP: {}", s);
-        for (varmer, _) in path.iter() {
-            print_varmer(varmer, true);
-            log::trace!("COV: {}", final_unitigs[*varmer].coverage);
+        for (node, _) in path.iter() {
+            print_varmer(final_unitigs.node(*node), true);
+            log::trace!("COV: {}", final_unitigs.coverage[*node as usize]);
         }
         let path_frag = DictFrag {
             seq: seq.iter().cloned().collect::<FxHashMap<u32, u8>>(),
@@ -1613,23 +2100,28 @@ fn get_path_haps<'a>(
             seq_vec: seq,
             cov: 0,
         };
-        path_frags.push(path_frag);
+        path_frags.push((path_frag, path.iter().map(|x| x.0).collect()));
     }
     path_frags.sort_by(|a, b| {
-        (a.first_position, a.last_position).cmp(&(b.first_position, b.last_position))
+        (a.0.first_position, a.0.last_position).cmp(&(b.0.first_position, b.0.last_position))
     });
     let mut final_results: Vec<HapFinalResult> = vec![];
-    for path_frag in path_frags.iter() {
+    for (path_frag, path) in path_frags.into_iter() {
         let final_res = HapFinalResult {
             relative_abundances: 0.,
             depth: 0.,
+            hapq: None,
+            em_abundance: None,
+            recombinant: None,
             assigned_frags: vec![],
-            path_frag: path_frag.clone(),
+            path_frag,
+            path,
         };
         final_results.push(final_res);
     }
 
-    reassign_frags(dbg_frags, &mut final_results, false);
+    let all_posteriors = all_hap_posteriors(dbg_frags, &final_results);
+    reassign_frags(dbg_frags, &all_posteriors, &mut final_results, false, options.min_posterior);
     //print relative percentage
     let total_cov = final_results
         .iter()
@@ -1669,68 +2161,287 @@ fn get_path_haps<'a>(
     return ret_results;
 }
 
-fn reassign_frags<'a>(dbg_frags: &'a Vec<FragDBG>, final_results: &mut Vec<HapFinalResult<'a>>, assign_ambiguous: bool) -> Vec<&'a FragDBG>{
-    let mut assignments = vec![vec![]; final_results.len()];
-    let mut unassignable = vec![];
-    let seq_lens = final_results
+//Log-likelihood of a read under each haplotype with the allele error model. Sites a haplotype
+//has no allele for count as a coin flip. None if no haplotype explains the read better than
+//coin flips at all its sites.
+fn hap_lls(frag: &FragDBG, final_results: &[HapFinalResult]) -> Option<Vec<f64>> {
+    let null_ll = frag.seq.len() as f64 * constants::MISSING_SITE_LN_LIKELIHOOD;
+    let lls = final_results
         .iter()
-        .map(|x| x.path_frag.seq.len())
-        .collect::<Vec<usize>>();
-    let seq_covs = final_results
+        .map(|res| {
+            frag.seq
+                .iter()
+                .map(|(pos, _)| match res.path_frag.seq.get(pos) {
+                    Some(hap_geno) => utils_frags::allele_ln_likelihood_dbg(frag, pos, *hap_geno),
+                    None => constants::MISSING_SITE_LN_LIKELIHOOD,
+                })
+                .sum::<f64>()
+        })
+        .collect::<Vec<f64>>();
+    if lls.iter().all(|ll| *ll <= null_ll) {
+        return None;
+    }
+    Some(lls)
+}
+
+//Posterior probability of each haplotype for a read, with the haplotypes' abundances as the
+//prior (uniform while any abundance is unknown). None if no haplotype explains the read.
+fn hap_posteriors(frag: &FragDBG, final_results: &[HapFinalResult]) -> Option<Vec<f64>> {
+    let lls = hap_lls(frag, final_results)?;
+    let uniform_prior = final_results.iter().any(|res| res.relative_abundances <= 0.);
+    let log_joint = lls
         .iter()
-        .map(|x| x.relative_abundances)
+        .zip(final_results.iter())
+        .map(|(ll, res)| if uniform_prior { *ll } else { ll + res.relative_abundances.ln() })
         .collect::<Vec<f64>>();
+    let log_evidence = utils_frags::log_sum_exp(&log_joint);
+    Some(log_joint.iter().map(|x| (x - log_evidence).exp()).collect())
+}
 
+//hap_posteriors of every read, in the order of dbg_frags.
+fn all_hap_posteriors(dbg_frags: &[FragDBG], final_results: &[HapFinalResult]) -> Vec<Option<Vec<f64>>> {
+    dbg_frags
+        .par_iter()
+        .map(|frag| hap_posteriors(frag, final_results))
+        .collect()
+}
 
-    for frag in dbg_frags {
-        let mut ambig = false;
-        let mut best_score = 0;
-        let mut best_index = 0;
-        for (i, res) in final_results.iter_mut().enumerate() {
-            let mut score = 0;
-            for (pos, geno) in frag.seq.iter() {
-                if res.path_frag.seq.contains_key(pos) {
-                    if *geno == *res.path_frag.seq.get(pos).unwrap() {
-                        score += 2;
-                    } else {
-                        // if fragments genotype is 0, possible reference bias
-                        if *geno == 0{
-                            score -= 3
-                        }
-                        else{
-                            score -= 5
-                        }
-                    }
-                } else {
-                    score -= 1;
-                }
+//Abundances (in %) of the haplotypes by expectation-maximization over all reads a haplotype
+//explains, ambiguous ones included, which are split between haplotypes by their posteriors.
+//Reads are weighted by their number of sites, like the depth-based abundances.
+fn em_abundances(final_results: &mut [HapFinalResult], dbg_frags: &[FragDBG]) {
+    let reads = dbg_frags
+        .par_iter()
+        .filter_map(|frag| hap_lls(frag, final_results).map(|lls| (frag.seq.len() as f64, lls)))
+        .collect::<Vec<_>>();
+    if reads.is_empty() {
+        return;
+    }
+    let total_weight = reads.iter().map(|(weight, _)| weight).sum::<f64>();
+    let mut abundances = vec![1. / final_results.len() as f64; final_results.len()];
+    for iteration in 0..constants::EM_MAX_ITERATIONS {
+        let mut new_abundances = vec![0.; final_results.len()];
+        for (weight, lls) in reads.iter() {
+            let log_joint = lls
+                .iter()
+                .zip(abundances.iter())
+                .map(|(ll, abundance)| ll + abundance.ln())
+                .collect::<Vec<f64>>();
+            let log_evidence = utils_frags::log_sum_exp(&log_joint);
+            for (new_abundance, x) in new_abundances.iter_mut().zip(log_joint.iter()) {
+                *new_abundance += weight * (x - log_evidence).exp() / total_weight;
             }
-            if score > best_score {
-                best_score = score;
-                best_index = i;
-                ambig = false;
-            } else if score == best_score {
-                ambig = true;
-                if res.path_frag.seq.len() as f64 * seq_covs[i] > seq_lens[best_index] as f64 * seq_covs[best_index] {
-                    best_index = i;
+        }
+        let change = abundances
+            .iter()
+            .zip(new_abundances.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f64::max);
+        abundances = new_abundances;
+        if change < constants::EM_TOLERANCE {
+            log::debug!("EM abundances converged after {} iterations", iteration + 1);
+            break;
+        }
+    }
+    for (res, abundance) in final_results.iter_mut().zip(abundances) {
+        res.em_abundance = Some(100. * abundance);
+    }
+}
+
+//Most probable haplotype; the first one on ties.
+fn best_posterior(posteriors: &[f64]) -> usize {
+    (0..posteriors.len())
+        .rev()
+        .max_by(|a, b| posteriors[*a].total_cmp(&posteriors[*b]))
+        .unwrap()
+}
+
+//HAPQ of each haplotype, from 0 to constants::HAPQ_CONSTANT. It is the product of
+//- the fraction of reads supporting the haplotype whose posterior for it is >= min_posterior,
+//  where ambiguous reads support every haplotype with posterior >= 1 - min_posterior,
+//- 1 - 0.5^(u/2) for u such unambiguous reads,
+//- 1 - 0.5^d for the fewest sites d at which the haplotype differs from another one, and
+//- the fraction of the haplotype's alleles in unitigs of the graph consistent with it.
+fn compute_hapqs(
+    final_results: &mut [HapFinalResult],
+    all_posteriors: &[Option<Vec<f64>>],
+    graph: &VarmerGraph,
+    min_posterior: f64,
+) {
+    let mut unique = vec![0; final_results.len()];
+    let mut ambiguous = vec![0; final_results.len()];
+    for posteriors in all_posteriors.iter().flatten() {
+        let best = best_posterior(posteriors);
+        if posteriors[best] >= min_posterior {
+            unique[best] += 1;
+        } else {
+            for (i, posterior) in posteriors.iter().enumerate() {
+                if *posterior >= 1. - min_posterior {
+                    ambiguous[i] += 1;
                 }
             }
         }
-        if best_score == 0 {
+    }
+
+    let mut hapqs = vec![];
+    for (i, res) in final_results.iter().enumerate() {
+        let hap = &res.path_frag;
+        let min_distance = final_results
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, other)| {
+                hap.seq_vec
+                    .iter()
+                    .filter(|(pos, geno)| other.path_frag.seq.get(pos).is_some_and(|x| x != geno))
+                    .count()
+            })
+            .min();
+        let walk = thread_haplotype(graph, hap);
+        let in_graph = hap
+            .seq_vec
+            .iter()
+            .filter(|(pos, _)| walk.iter().any(|x| graph.node(*x).iter().any(|y| y.0 == *pos)))
+            .count();
+
+        let reads = unique[i] + ambiguous[i];
+        let unique_fraction = if reads == 0 { 0. } else { unique[i] as f64 / reads as f64 };
+        let unique_support = 1. - 0.5f64.powf(unique[i] as f64 / 2.);
+        let separation = min_distance.map_or(1., |d| 1. - 0.5f64.powi(d as i32));
+        let graph_support = if hap.seq_vec.is_empty() { 0. } else { in_graph as f64 / hap.seq_vec.len() as f64 };
+        let hapq = constants::HAPQ_CONSTANT * unique_fraction * unique_support * separation * graph_support;
+        log::trace!(
+            "HAPQ {}: {:.0}, UNIQUE {} AMBIGUOUS {} DISTANCE {:?} IN GRAPH {}/{}",
+            i,
+            hapq,
+            unique[i],
+            ambiguous[i],
+            min_distance,
+            in_graph,
+            hap.seq_vec.len()
+        );
+        hapqs.push(hapq.round() as u8);
+    }
+    for (res, hapq) in final_results.iter_mut().zip(hapqs) {
+        res.hapq = Some(hapq);
+    }
+}
+
+//Assign each read to its most probable haplotype, given the posteriors of all reads from
+//all_hap_posteriors. Reads no haplotype explains are returned as unassignable, as are reads with a
+//posterior < min_posterior unless assign_ambiguous.
+fn reassign_frags<'a>(
+    dbg_frags: &'a [FragDBG],
+    all_posteriors: &[Option<Vec<f64>>],
+    final_results: &mut [HapFinalResult<'a>],
+    assign_ambiguous: bool,
+    min_posterior: f64,
+) -> Vec<&'a FragDBG> {
+    let mut assignments = vec![vec![]; final_results.len()];
+    let mut unassignable = vec![];
+
+    for (frag, posteriors) in dbg_frags.iter().zip(all_posteriors) {
+        let Some(posteriors) = posteriors else {
             unassignable.push(frag);
             continue;
-        }
-        if ambig && !assign_ambiguous {
+        };
+        let best_index = best_posterior(posteriors);
+        if posteriors[best_index] < min_posterior && !assign_ambiguous {
             unassignable.push(frag);
             continue;
         }
-
         assignments[best_index].push(frag);
     }
     for (i, assignment) in assignments.into_iter().enumerate() {
         final_results[i].assigned_frags = assignment;
     }
-    return unassignable;
+    unassignable
+}
+
+//Append each read's input file index, haplotype, posterior and the posteriors of all haplotypes
+//(in output order) to read_posteriors.tsv. A read is ambiguous if its posterior is < --min-posterior.
+fn write_read_posteriors(
+    final_results: &[HapFinalResult],
+    dbg_frags: &[FragDBG],
+    all_posteriors: &[Option<Vec<f64>>],
+    options: &Options,
+    contig_range: (&str, Option<(usize, usize)>),
+) {
+    let (start, end) = match contig_range.1 {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let posterior_file = Path::new(&options.output_dir).join("read_posteriors.tsv");
+    let exists = posterior_file.exists();
+    let mut posterior_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&posterior_file)
+            .expect("Could not open read posterior file"),
+    );
+    if !exists {
+        posterior_writer
+            .write_all(b"#contig\trange\tsource\tread\thaplotype\tposterior\tstatus\tposteriors\n")
+            .unwrap();
+    }
+    let mut assigned_hap = FxHashMap::default();
+    for (i, res) in final_results.iter().enumerate() {
+        for frag in res.assigned_frags.iter() {
+            assigned_hap.insert(frag.counter_id, i);
+        }
+    }
+    for (frag, posteriors) in dbg_frags.iter().zip(all_posteriors) {
+        let (hap, posterior, status, posteriors) = match (assigned_hap.get(&frag.counter_id), posteriors) {
+            (Some(i), Some(posteriors)) => (
+                format!("{}", i),
+                format!("{:.4}", posteriors[*i]),
+                if posteriors[*i] < options.min_posterior { "ambiguous" } else { "unique" },
+                posteriors.iter().map(|x| format!("{:.4}", x)).collect::<Vec<String>>().join(","),
+            ),
+            _ => (String::from("unassigned"), String::from("NA"), "unassigned", String::from("NA")),
+        };
+        writeln!(
+            posterior_writer,
+            "{}\t{}-{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            contig_range.0, start, end, frag.source, frag.id, hap, posterior, status, posteriors
+        )
+        .unwrap();
+    }
+}
+
+//With several input files (-b), write which file each haplotype's reads came from.
+fn write_read_sources(
+    final_results: &[HapFinalResultString],
+    options: &Options,
+    contig_range: (&str, Option<(usize,usize)>),
+) {
+    let (start, end) = match contig_range.1 {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let source_file = Path::new(&options.output_dir).join("read_sources.tsv");
+    let exists = source_file.exists();
+    let mut source_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&source_file)
+            .expect("Could not open read source file"),
+    );
+    if !exists {
+        source_writer.write_all(b"#contig\trange\thaplotype\tread\tinput_file\n").unwrap();
+    }
+    for (i, res) in final_results.iter().enumerate() {
+        for (source, id) in res.assigned_frags.iter() {
+            writeln!(
+                source_writer,
+                "{}\t{}-{}\t{}\t{}\t{}",
+                contig_range.0, start, end, i, id, options.bam_file[*source]
+            )
+            .unwrap();
+        }
+    }
 }
 
 fn print_final_hap_results(
@@ -1812,8 +2523,16 @@ fn print_final_hap_results(
         haplotype_writer
             .write_all(
                 format!(
-                    ">Contig:{},Range:{}-{},Haplotype:{},Abundance:{:.2},Depth:{:.2}\n",
-                    contig_name, start,end, i, res.relative_abundances, res.depth
+                    ">Contig:{},Range:{}-{},Haplotype:{},Abundance:{:.2}{},Depth:{:.2}{}{}\n",
+                    contig_name,
+                    start,
+                    end,
+                    i,
+                    res.relative_abundances,
+                    res.em_abundance.map_or(String::new(), |x| format!(",EMAbundance:{:.2}", x)),
+                    res.depth,
+                    res.hapq.map_or(String::new(), |x| format!(",HAPQ:{}", x)),
+                    res.recombinant.map_or(String::new(), |(a, b)| format!(",Recombinant:{}+{}", a, b))
                 )
                 .as_bytes(),
             )
@@ -1821,11 +2540,18 @@ fn print_final_hap_results(
         let seq = &res.path_frag.seq;
         let mut printable_seq = vec![b'-'; snps];
         if options.allele_output{
+            //Each site is padded to its longest allele so that indel haplotypes stay aligned.
             let pos_to_allele = &vcf_profile.vcf_pos_allele_map[contig_name];
-            for (pos, geno) in seq.iter() {
-                let seq_pos = snp_pos_to_genome_pos[*pos as usize - 1];
-                let allele = pos_to_allele[&seq_pos][*geno as usize];
-                printable_seq[*pos as usize - 1] = allele;
+            printable_seq = vec![];
+            for i in 0..snps {
+                let alleles = &pos_to_allele[&snp_pos_to_genome_pos[i]];
+                let width = alleles.iter().map(|x| x.len()).max().unwrap();
+                let mut column = vec![b'-'; width];
+                if let Some(geno) = seq.get(&(i as u32 + 1)) {
+                    let allele = &alleles[*geno as usize];
+                    column[..allele.len()].copy_from_slice(allele);
+                }
+                printable_seq.extend(column);
             }
         }
         else{
@@ -1835,8 +2561,8 @@ fn print_final_hap_results(
         }
         //print wrapped lines of 80
         let mut j = 0;
-        while j < snps {
-            let end = std::cmp::min(j + 80, snps);
+        while j < printable_seq.len() {
+            let end = std::cmp::min(j + 80, printable_seq.len());
             haplotype_writer.write_all(&printable_seq[j..end]).unwrap();
             haplotype_writer.write_all(b"\n").unwrap();
             j += 80;
@@ -1901,84 +2627,49 @@ fn print_final_hap_results(
 }
 
 fn clean_hanging_kmers(
-    unitigs: FxHashMap<VarMer, DBGInfo>,
+    unitigs: &VarmerGraph,
     k: usize,
-) -> FxHashMap<VarMer, DBGInfo> {
-    let mut int_rep: FxHashMap<usize, (Vec<usize>, Vec<usize>, u64)> = FxHashMap::default();
-    let unitigs_to_ints: FxHashMap<&VarMer, usize> = unitigs
-        .iter()
-        .enumerate()
-        .map(|(i, (varmer, _))| (varmer, i))
-        .collect();
-
-    for (varmer, info) in unitigs.iter() {
-        let mut in_ints = vec![];
-        let mut out_ints = vec![];
-        for in_varmer in info.in_varmers.iter() {
-            in_ints.push(*unitigs_to_ints.get(&**in_varmer).unwrap());
-        }
-        for out_varmer in info.out_varmers.iter() {
-            out_ints.push(*unitigs_to_ints.get(&**out_varmer).unwrap());
-        }
-        int_rep.insert(
-            unitigs_to_ints.get(varmer).unwrap().clone(),
-            (in_ints, out_ints, info.coverage),
-        );
-    }
-
-    let mut new_unitigs_map = FxHashMap::default();
-
-    let mut new_unitigs = FxHashMap::default();
+) -> VarmerGraph {
     let k_l = k / 2;
     let k_r = k - k_l;
 
-    for (varmer, info) in unitigs.iter() {
-        let ind = unitigs_to_ints.get(varmer).unwrap();
-        let cut_varmer;
-        if info.in_varmers.len() == 0 && info.out_varmers.len() == 0 {
-            cut_varmer = varmer.clone();
-        } else if info.in_varmers.len() != 0 && info.out_varmers.len() != 0 {
-            cut_varmer = varmer[k_l..varmer.len() - k_r]
-                .iter()
-                .cloned()
-                .collect::<VarMer>();
-        } else if info.in_varmers.len() == 0 && info.out_varmers.len() != 0 {
-            cut_varmer = varmer[..varmer.len() - k_r]
-                .iter()
-                .cloned()
-                .collect::<VarMer>();
-        } else if info.in_varmers.len() != 0 && info.out_varmers.len() == 0 {
-            cut_varmer = varmer[k_l..].iter().cloned().collect::<VarMer>();
-        } else {
-            cut_varmer = varmer.clone();
-        }
-        new_unitigs_map.insert(ind, cut_varmer.clone());
-        new_unitigs.insert(cut_varmer, DBGInfo::default());
+    let mut cut_varmers = vec![];
+    for node in unitigs.ids() {
+        let varmer = unitigs.node(node);
+        let has_in = !unitigs.in_edges[node as usize].is_empty();
+        let has_out = !unitigs.out_edges[node as usize].is_empty();
+        let cut_varmer = match (has_in, has_out) {
+            (true, true) => &varmer[k_l..varmer.len() - k_r],
+            (false, true) => &varmer[..varmer.len() - k_r],
+            (true, false) => &varmer[k_l..],
+            (false, false) => varmer,
+        };
+        cut_varmers.push(cut_varmer);
     }
 
-    for (ind, (in_ints, out_ints, cov)) in int_rep.iter() {
-        let mut new_in_ints = vec![];
-        let mut new_out_ints = vec![];
-        for in_int in in_ints.iter() {
-            if new_unitigs_map.contains_key(in_int) {
-                new_in_ints.push(*in_int);
-            }
-        }
-        for out_int in out_ints.iter() {
-            if new_unitigs_map.contains_key(out_int) {
-                new_out_ints.push(*out_int);
-            }
-        }
-        let varmer = new_unitigs_map.get(ind).unwrap().clone();
-        new_unitigs.get_mut(&varmer).unwrap().in_varmers = new_in_ints
-            .iter()
-            .map(|x| Arc::new(new_unitigs_map.get(x).unwrap().clone()))
-            .collect();
-        new_unitigs.get_mut(&varmer).unwrap().out_varmers = new_out_ints
-            .iter()
-            .map(|x| Arc::new(new_unitigs_map.get(x).unwrap().clone()))
-            .collect();
-        new_unitigs.get_mut(&varmer).unwrap().coverage = *cov;
+    //Nodes are laid out as in distinct_varmer_graph, by the first unitig with each cut varmer.
+    let mut first_with_cut = FxHashMap::default();
+    for (node, cut_varmer) in cut_varmers.iter().enumerate() {
+        first_with_cut.entry(*cut_varmer).or_insert(node);
+    }
+    let kept = first_with_cut.values().copied().collect::<Vec<usize>>();
+    let mut new_ids = vec![0; unitigs.len()];
+    for (new_id, node) in kept.iter().enumerate() {
+        new_ids[*node] = new_id as NodeId;
+    }
+    for (node, cut_varmer) in cut_varmers.iter().enumerate() {
+        new_ids[node] = new_ids[first_with_cut[cut_varmer]];
+    }
+
+    //Unitigs cut to the same varmer share a node, which takes the coverage and edges of the
+    //last of them in hash order of their indices.
+    let mut new_unitigs = VarmerGraph::from_nodes(kept.iter().map(|node| (cut_varmers[*node], 0)));
+    let hash_order = (0..unitigs.len()).collect::<FxHashSet<usize>>();
+    for node in hash_order {
+        let new_id = new_ids[node] as usize;
+        new_unitigs.coverage[new_id] = unitigs.coverage[node];
+        new_unitigs.out_edges[new_id] = unitigs.out_edges[node].iter().map(|x| new_ids[*x as usize]).collect();
+        new_unitigs.in_edges[new_id] = unitigs.in_edges[node].iter().map(|x| new_ids[*x as usize]).collect();
     }
 
     return new_unitigs;
@@ -2107,16 +2798,10 @@ fn get_outside_paths_and_integers(
 
 fn get_assembly_integer_graph(
     integer_paths: &Vec<VarmerPathInteger>,
-) -> FxHashMap<VarMer, DBGInfo> {
-    let mut assembly_graph: FxHashMap<VarMer, DBGInfo> = FxHashMap::default();
+) -> VarmerGraph {
+    let mut nodes = vec![];
     for path1 in integer_paths.iter() {
-        assembly_graph
-            .entry(path1.intver.clone())
-            .or_insert(DBGInfo {
-                out_varmers: vec![],
-                in_varmers: vec![],
-                coverage: path1.total_avg_cov,
-            });
+        nodes.push((path1.intver.as_slice(), path1.total_avg_cov));
         log::trace!("INTEGER PATH - FIRST {}, LAST {}, total_avg_cov {}", path1.first, path1.last, path1.total_avg_cov);
         let mut string = String::new();
         for integer in path1.intver.iter() {
@@ -2124,6 +2809,7 @@ fn get_assembly_integer_graph(
         }
         log::trace!("{}", string);
     }
+    let mut assembly_graph = distinct_varmer_graph(&nodes);
     let data = integer_paths
         .iter().enumerate()
         .map(|(y,x)| Interval{start: x.first - 1,
@@ -2150,10 +2836,9 @@ fn get_assembly_integer_graph(
             }
             if overlap_len > 0 {
                 log::trace!("OVERLAP LEN {}", overlap_len);
-                let info1 = assembly_graph.get_mut(&path1.intver).unwrap();
-                info1.out_varmers.push(Arc::new(path2.intver.clone()));
-                let info2 = assembly_graph.get_mut(&path2.intver).unwrap();
-                info2.in_varmers.push(Arc::new(path1.intver.clone()));
+                let node1 = assembly_graph.find(&path1.intver).unwrap();
+                let node2 = assembly_graph.find(&path2.intver).unwrap();
+                assembly_graph.add_edge(node1, node2);
             }
         }
     }
@@ -2171,6 +2856,7 @@ fn subsample_positions_fragdbg(
         let mut new_seq_dict = FxHashMap::default();
         let mut new_snp_pos_to_seq_pos = FxHashMap::default();
         let mut new_qual_dict = FxHashMap::default();
+        let mut new_allele_lls = FxHashMap::default();
 
         for (pos, geno) in frag.seq.iter() {
             if positions.contains(pos) {
@@ -2179,6 +2865,9 @@ fn subsample_positions_fragdbg(
                 new_seq_dict.insert(new_pos, *geno);
                 new_snp_pos_to_seq_pos.insert(new_pos, frag.snp_pos_to_seq_pos[pos]);
                 new_qual_dict.insert(new_pos, frag.qual_dict[pos]);
+                if let Some(lls) = frag.allele_lls.remove(pos) {
+                    new_allele_lls.insert(new_pos, lls);
+                }
             }
         }
 
@@ -2196,6 +2885,7 @@ fn subsample_positions_fragdbg(
         frag.seq = new_seq;
         frag.seq_dict = new_seq_dict;
         frag.qual_dict = new_qual_dict;
+        frag.allele_lls = new_allele_lls;
         frag.snp_pos_to_seq_pos = new_snp_pos_to_seq_pos;
         frag.first_position = new_first_pos;
         frag.last_position = new_last_pos;
@@ -2211,51 +2901,357 @@ fn subsample_positions_fragdbg(
 }
 
 fn remove_tips(
-    unitigs: &FxHashMap<VarMer, DBGInfo>,
+    unitigs: &VarmerGraph,
     _options: &Options,
     k: usize,
-) -> Vec<VarMer> {
+) -> Vec<(NodeId, UnitigRemoval)> {
 
     let mut bad_unitigs = vec![];
     //Prev-tip
-    for varmer in unitigs.keys(){
+    for node in unitigs.ids(){
+        let varmer = unitigs.node(node);
         //Only pop tips for deletions
         if varmer.len() == varmer.last().unwrap().0 as usize - varmer[0].0 as usize + 1{
             continue
         }
-        let into = &unitigs[varmer].in_varmers;
-        let out = &unitigs[varmer].out_varmers;
-        let cov = unitigs[varmer].coverage;
-        if into.len() == 0 && out.len() == 1{
-            let test_cov = unitigs[out[0].as_ref()].coverage;
+        let into = &unitigs.in_edges[node as usize];
+        let out = &unitigs.out_edges[node as usize];
+        let cov = unitigs.coverage[node as usize];
+        let removal = |test_cov: u64, p_value: f64| UnitigRemoval {
+            step: String::from("remove_tips"),
+            varmer: varmer.to_vec(),
+            coverage: cov,
+            test_coverage: test_cov,
+            error_rate: Some(constants::TIP_ERROR_RATE),
+            p_value: Some(p_value),
+        };
+        if into.is_empty() && out.len() == 1{
+            let test_cov = unitigs.coverage[out[0] as usize];
             //only goes 1 k-mer back
-            if varmer[0].0 + k as u32 > out[0][0].0{
-                if binomial_test(test_cov, cov, 0.10) > 0.005{
-                    bad_unitigs.push(varmer.clone());
+            if varmer[0].0 + k as u32 > unitigs.node(out[0])[0].0{
+                let p_value = binomial_test(test_cov, cov, constants::TIP_ERROR_RATE);
+                if p_value > 0.005{
+                    bad_unitigs.push((node, removal(test_cov, p_value)));
                 }
             }
         }
-        if into.len() == 1 && out.len() == 0{
-            let test_cov = unitigs[into[0].as_ref()].coverage;
+        if into.len() == 1 && out.is_empty(){
+            let test_cov = unitigs.coverage[into[0] as usize];
             // only goes 1 k-mer forward
-            if varmer.last().unwrap().0 < into[0].last().unwrap().0 + k as u32{
-                if binomial_test(test_cov, cov, 0.10) > 0.005{
-                    bad_unitigs.push(varmer.clone());
+            if varmer.last().unwrap().0 < unitigs.node(into[0]).last().unwrap().0 + k as u32{
+                let p_value = binomial_test(test_cov, cov, constants::TIP_ERROR_RATE);
+                if p_value > 0.005{
+                    bad_unitigs.push((node, removal(test_cov, p_value)));
                 }
             }
         }
     }
 
-    for bad_unitig in bad_unitigs.iter(){
+    for (bad_unitig, _) in bad_unitigs.iter(){
         log::trace!("REMOVED TIP");
-        print_varmer(bad_unitig, true);
+        print_varmer(unitigs.node(*bad_unitig), true);
     }
 
     return bad_unitigs;
 }
+
+//A unitig deleted while cleaning the unitig graph, with the step and test that deleted it.
+pub struct UnitigRemoval {
+    pub step: String,
+    pub varmer: Vec<(SnpPosition, Genotype)>,
+    pub coverage: u64,
+    //Coverage the unitig was tested against: of its DP hits in query_unitigs, of its neighbour in
+    //remove_tips, and the minimum coverage for small disconnected unitigs.
+    pub test_coverage: u64,
+    //Error rate and p-value of the binomial test; None for a coverage cutoff.
+    pub error_rate: Option<f64>,
+    pub p_value: Option<f64>,
+}
+
+//A branch of a bubble: the chain of unitigs between a source unitig with several out-edges and
+//a sink unitig where the branches join.
+struct BubbleBranch {
+    varmer: Vec<(SnpPosition, Genotype)>,
+    //Alleles of the branch not shared with the source or sink unitig.
+    inner: std::ops::Range<usize>,
+    coverage: u64,
+    //Binomial test of the branch's coverage as errors of the branch with the most coverage, as
+    //in query_unitigs. None for that branch.
+    p_value: Option<f64>,
+    unitigs: Vec<Vec<(SnpPosition, Genotype)>>,
+}
+
+//Bubbles of a unitig graph with k-mers of size k. A bubble starts at a unitig with several
+//out-edges and ends at the first unitig (in position order) that at least two of its out-edges
+//reach within constants::MAX_BUBBLE_UNITIGS unitigs. Its branches are the paths between the two
+//through at least one unitig. Bubbles can be nested.
+fn find_bubbles(unitigs: &VarmerGraph, k: usize) -> Vec<Vec<BubbleBranch>> {
+    let overlap = k - 1;
+    let mut rank = vec![0; unitigs.len()];
+    for (i, node) in unitigs.sorted_ids().iter().enumerate() {
+        rank[*node as usize] = i;
+    }
+
+    let mut bubbles = vec![];
+    //Sources joined to the same unitigs give the same branches; list them once.
+    let mut seen_chains = FxHashSet::default();
+    for &source in unitigs.sorted_ids() {
+        let outs = &unitigs.out_edges[source as usize];
+        if outs.len() < 2 {
+            continue;
+        }
+        //Number of out-edges of the source that reach each unitig.
+        let mut reached_by = FxHashMap::default();
+        for &child in outs.iter() {
+            let mut reached = FxHashSet::default();
+            let mut frontier = vec![child];
+            for _ in 0..constants::MAX_BUBBLE_UNITIGS {
+                let mut next_frontier = vec![];
+                for node in frontier {
+                    for &next in unitigs.out_edges[node as usize].iter() {
+                        if reached.insert(next) {
+                            next_frontier.push(next);
+                        }
+                    }
+                }
+                frontier = next_frontier;
+            }
+            for node in reached {
+                *reached_by.entry(node).or_insert(0) += 1;
+            }
+        }
+        let sink = reached_by
+            .iter()
+            .filter(|(_, count)| **count >= 2)
+            .map(|(node, _)| *node)
+            .min_by_key(|node| rank[*node as usize]);
+        let Some(sink) = sink else {
+            continue;
+        };
+
+        let mut chains = vec![];
+        let mut stack = outs.iter().rev().map(|x| vec![*x]).collect::<Vec<_>>();
+        while let Some(chain) = stack.pop() {
+            if chains.len() == constants::MAX_BUBBLE_BRANCHES {
+                break;
+            }
+            let last = *chain.last().unwrap();
+            for &next in unitigs.out_edges[last as usize].iter().rev() {
+                if next == sink {
+                    chains.push(chain.clone());
+                } else if chain.len() < constants::MAX_BUBBLE_UNITIGS && rank[next as usize] < rank[sink as usize] {
+                    let mut extended = chain.clone();
+                    extended.push(next);
+                    stack.push(extended);
+                }
+            }
+        }
+        chains.truncate(constants::MAX_BUBBLE_BRANCHES);
+        if chains.len() < 2 || !seen_chains.insert(chains.clone()) {
+            continue;
+        }
+
+        let mut branches = chains
+            .iter()
+            .map(|chain| {
+                let mut varmer = unitigs.node(chain[0]).to_vec();
+                for node in chain[1..].iter() {
+                    varmer.extend_from_slice(&unitigs.node(*node)[overlap..]);
+                }
+                let inner = if varmer.len() > 2 * overlap {
+                    overlap..varmer.len() - overlap
+                } else {
+                    0..varmer.len()
+                };
+                let coverage = chain.iter().map(|x| unitigs.coverage[*x as usize]).min().unwrap();
+                BubbleBranch {
+                    varmer,
+                    inner,
+                    coverage,
+                    p_value: None,
+                    unitigs: chain.iter().map(|x| unitigs.node(*x).to_vec()).collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let major = (0..branches.len()).rev().max_by_key(|i| branches[*i].coverage).unwrap();
+        let major_d = varmer_to_dictfrag(&branches[major].varmer);
+        let major_cov = branches[major].coverage;
+        for (i, branch) in branches.iter_mut().enumerate() {
+            if i == major {
+                continue;
+            }
+            let (mut rtoa, mut ator, mut dels) = (0, 0, 0);
+            compare_alleles(&varmer_to_dictfrag(&branch.varmer), &major_d, |_, site| match site {
+                SiteMatch::RefToAlt => rtoa += 1,
+                SiteMatch::AltToRef => ator += 1,
+                SiteMatch::Del => dels += 1,
+                SiteMatch::Same => {}
+            });
+            branch.p_value = Some(binomial_test(major_cov, branch.coverage, error_rate(rtoa, ator, dels)));
+        }
+        bubbles.push(branches);
+    }
+    bubbles
+}
+
+fn varmer_to_dictfrag(varmer: &[(SnpPosition, Genotype)]) -> DictFrag {
+    DictFrag {
+        seq: varmer.iter().cloned().collect(),
+        seq_vec: varmer.to_vec(),
+        first_position: varmer.first().unwrap().0,
+        last_position: varmer.last().unwrap().0,
+        cov: 0,
+    }
+}
+
+//Append one row per bubble branch to bubbles.tsv. A branch is popped by the first removal whose
+//unitig contains one of its unitigs, and is kept if its alleles are all in the cleaned graph.
+fn write_bubbles(
+    bubbles: &[Vec<BubbleBranch>],
+    removals: &[UnitigRemoval],
+    cleaned: &VarmerGraph,
+    snp_pos_to_genome_pos: &[usize],
+    options: &Options,
+    contig_range: (&str, Option<(usize, usize)>),
+) {
+    let (start, end) = match contig_range.1 {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let bubble_file = Path::new(&options.output_dir).join("bubbles.tsv");
+    let exists = bubble_file.exists();
+    let mut bubble_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&bubble_file)
+            .expect("Could not open bubble file"),
+    );
+    if !exists {
+        bubble_writer
+            .write_all(b"#contig\trange\tbubble\tsnp_range\tgenome_range\tbranch\talleles\tcoverage\tp_value\tstatus\tremoved_by\tremoval_p_value\n")
+            .unwrap();
+    }
+    let cleaned_alleles = cleaned
+        .ids()
+        .flat_map(|node| cleaned.node(node).iter().cloned())
+        .collect::<FxHashSet<_>>();
+    let genome_pos = |snp_pos: u32| snp_pos_to_genome_pos[snp_pos as usize - 1] + 1;
+    for (i, branches) in bubbles.iter().enumerate() {
+        let first = branches.iter().map(|x| x.varmer[x.inner.start].0).min().unwrap();
+        let last = branches.iter().map(|x| x.varmer[x.inner.end - 1].0).max().unwrap();
+        for (j, branch) in branches.iter().enumerate() {
+            let inner = &branch.varmer[branch.inner.clone()];
+            let alleles = inner
+                .iter()
+                .map(|(pos, geno)| format!("{}:{}", pos, geno))
+                .collect::<Vec<String>>()
+                .join(",");
+            let p_value = match branch.p_value {
+                Some(p) => format!("{:.3e}", p),
+                None => String::from("NA"),
+            };
+            let removal = removals.iter().find(|removal| {
+                branch
+                    .unitigs
+                    .iter()
+                    .any(|unitig| removal.varmer.windows(unitig.len()).any(|x| x == unitig.as_slice()))
+            });
+            let status = if removal.is_none() && inner.iter().all(|allele| cleaned_alleles.contains(allele)) {
+                "kept"
+            } else {
+                "popped"
+            };
+            let (removed_by, removal_p_value) = match removal {
+                Some(removal) => (
+                    removal.step.as_str(),
+                    removal.p_value.map_or(String::from("NA"), |p| format!("{:.3e}", p)),
+                ),
+                None => ("NA", String::from("NA")),
+            };
+            writeln!(
+                bubble_writer,
+                "{}\t{}-{}\t{}\t{}-{}\t{}-{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                contig_range.0,
+                start,
+                end,
+                i,
+                first,
+                last,
+                genome_pos(first),
+                genome_pos(last),
+                j,
+                alleles,
+                branch.coverage,
+                p_value,
+                status,
+                removed_by,
+                removal_p_value
+            )
+            .unwrap();
+        }
+    }
+}
+
+//Append one row per unitig deleted while cleaning the unitig graph to removed_unitigs.tsv, with
+//the step that deleted it and its test.
+fn write_removed_unitigs(
+    removals: &[UnitigRemoval],
+    snp_pos_to_genome_pos: &[usize],
+    options: &Options,
+    contig_range: (&str, Option<(usize, usize)>),
+) {
+    let (start, end) = match contig_range.1 {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let removal_file = Path::new(&options.output_dir).join("removed_unitigs.tsv");
+    let exists = removal_file.exists();
+    let mut removal_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&removal_file)
+            .expect("Could not open removed unitig file"),
+    );
+    if !exists {
+        removal_writer
+            .write_all(b"#contig\trange\tstep\tsnp_range\tgenome_range\talleles\tcoverage\ttest_coverage\terror_rate\tp_value\n")
+            .unwrap();
+    }
+    let genome_pos = |snp_pos: u32| snp_pos_to_genome_pos[snp_pos as usize - 1] + 1;
+    for removal in removals.iter() {
+        let first = removal.varmer.first().unwrap().0;
+        let last = removal.varmer.last().unwrap().0;
+        let alleles = removal
+            .varmer
+            .iter()
+            .map(|(pos, geno)| format!("{}:{}", pos, geno))
+            .collect::<Vec<String>>()
+            .join(",");
+        writeln!(
+            removal_writer,
+            "{}\t{}-{}\t{}\t{}-{}\t{}-{}\t{}\t{}\t{}\t{}\t{}",
+            contig_range.0,
+            start,
+            end,
+            removal.step,
+            first,
+            last,
+            genome_pos(first),
+            genome_pos(last),
+            alleles,
+            removal.coverage,
+            removal.test_coverage,
+            removal.error_rate.map_or(String::from("NA"), |e| format!("{:.3e}", e)),
+            removal.p_value.map_or(String::from("NA"), |p| format!("{:.3e}", p))
+        )
+        .unwrap();
+    }
+}
    
 fn strand_bias_filter(dbg_frags: &mut Vec<FragDBG>, options: &Options, num_snps: usize, snp_pos_to_gn: &Vec<usize>) -> Vec<usize>{
-    let mut pvalues = vec![];
     let mut snps_to_4_table: Vec<[u32;4]> = vec![[0; 4]; num_snps];
     for frag in dbg_frags.iter() {
         let ind;
@@ -2273,7 +3269,23 @@ fn strand_bias_filter(dbg_frags: &mut Vec<FragDBG>, options: &Options, num_snps:
             snps_to_4_table[(*snp_pos - 1) as usize][geno + ind] += 1; 
         }
     }
-    for (snp, table) in snps_to_4_table.iter().enumerate(){
+    let good_snps = strand_unbiased_sites(&snps_to_4_table, options.strand_bias_fdr)
+        .into_iter()
+        .map(|x| (x + 1) as u32)
+        .collect::<FxHashSet<u32>>();
+
+    let old_pos_to_new_pos_map = (1..=num_snps).filter(|x| good_snps.contains(&(*x as u32))).enumerate().map(|(i, x)| (x as u32, i as u32 + 1)).collect::<FxHashMap<u32, u32>>();
+    log::trace!("GOOD SNPS: {:?}", good_snps);
+
+    subsample_positions_fragdbg(dbg_frags, &good_snps, &old_pos_to_new_pos_map, snp_pos_to_gn)
+}
+
+//Fisher's exact test with a Benjamini-Hochberg style cutoff on 2x2 strand tables laid out as
+//[ref forward, alt forward, ref reverse, alt reverse]. Returns the indices of the sites that
+//are not strand biased.
+pub fn strand_unbiased_sites(tables: &[[u32; 4]], fdr: f64) -> FxHashSet<usize> {
+    let mut pvalues = vec![];
+    for (snp, table) in tables.iter().enumerate(){
         let p = fishers_exact(table).unwrap().two_tail_pvalue;
         pvalues.push((p, snp));
     }
@@ -2282,11 +3294,11 @@ fn strand_bias_filter(dbg_frags: &mut Vec<FragDBG>, options: &Options, num_snps:
     let mut good_snps = FxHashSet::default();
 
     for i in 0..pvalues.len(){
-        if pvalues[i].0 < options.strand_bias_fdr * (i as f64) / (pvalues.len() as f64){
+        if pvalues[i].0 < fdr * (i as f64) / (pvalues.len() as f64){
             for j in i..pvalues.len(){
                 log::trace!("THRESHOLD STRAND BIAS SNP {} : {}", pvalues[j].1 + 1, pvalues[j].0);
-                log::trace!("TABLE {:?}", snps_to_4_table[pvalues[j].1]);
-                let table = snps_to_4_table[pvalues[j].1];
+                log::trace!("TABLE {:?}", tables[pvalues[j].1]);
+                let table = tables[pvalues[j].1];
                 let ratio;
                 if table[1] == 0 || table[2] == 0{
                     ratio = f64::MAX;
@@ -2299,18 +3311,261 @@ fn strand_bias_filter(dbg_frags: &mut Vec<FragDBG>, options: &Options, num_snps:
 
                 //Require high odds ratio filter for high coverage datasets
                 if ratio < 1.5{
-                    good_snps.insert((pvalues[j].1 + 1) as u32);
+                    good_snps.insert(pvalues[j].1);
                 }
             }
             break;
         }
         else{
-            good_snps.insert((pvalues[i].1 + 1) as u32);
+            good_snps.insert(pvalues[i].1);
         }
     }
+    good_snps
+}
 
-    let old_pos_to_new_pos_map = (1..=num_snps).filter(|x| good_snps.contains(&(*x as u32))).enumerate().map(|(i, x)| (x as u32, i as u32 + 1)).collect::<FxHashMap<u32, u32>>();
-    log::trace!("GOOD SNPS: {:?}", good_snps);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn dict_frag(seq_vec: Vec<(SnpPosition, Genotype)>) -> DictFrag {
+        DictFrag {
+            seq: seq_vec.iter().copied().collect(),
+            first_position: seq_vec[0].0,
+            last_position: seq_vec.last().unwrap().0,
+            seq_vec,
+            cov: 0,
+        }
+    }
 
-    subsample_positions_fragdbg(dbg_frags, &good_snps, &old_pos_to_new_pos_map, snp_pos_to_gn)
+    fn frag_dbg(counter_id: usize, seq: Vec<(SnpPosition, Genotype)>) -> FragDBG {
+        FragDBG {
+            id: format!("read_{}", counter_id),
+            counter_id,
+            seq_dict: seq.iter().copied().collect(),
+            qual_dict: seq.iter().map(|(pos, _)| (*pos, 30)).collect(),
+            first_position: seq[0].0,
+            last_position: seq.last().unwrap().0,
+            seq,
+            ..Default::default()
+        }
+    }
+
+    fn hap_result<'a>(seq_vec: Vec<(SnpPosition, Genotype)>) -> HapFinalResult<'a> {
+        HapFinalResult {
+            relative_abundances: 0.,
+            depth: 0.,
+            hapq: None,
+            em_abundance: None,
+            recombinant: None,
+            assigned_frags: vec![],
+            path_frag: dict_frag(seq_vec),
+            path: vec![],
+        }
+    }
+
+    #[test]
+    fn hapq_is_high_for_unique_support_and_zero_for_ambiguous_haplotypes() {
+        let hap_a = (1..=10).map(|pos| (pos, 0)).collect::<Vec<_>>();
+        let hap_b = (1..=10).map(|pos| (pos, 1)).collect::<Vec<_>>();
+        //Differs from hap_a at SNP 10 only, which none of hap_a's reads cover.
+        let mut hap_c = hap_a.clone();
+        hap_c[9].1 = 1;
+        let mut frags = vec![];
+        for i in 0..20 {
+            frags.push(frag_dbg(2 * i, hap_a[..9].to_vec()));
+            frags.push(frag_dbg(2 * i + 1, hap_b.clone()));
+        }
+        let haps = [&hap_a, &hap_b, &hap_c];
+        let graph = VarmerGraph::from_nodes(haps.iter().map(|x| (x.as_slice(), 20)));
+
+        let mut results = vec![hap_result(hap_a.clone()), hap_result(hap_b.clone())];
+        let posteriors = all_hap_posteriors(&frags, &results);
+        compute_hapqs(&mut results, &posteriors, &graph, 0.9);
+        assert_eq!(results.iter().map(|x| x.hapq).collect::<Vec<_>>(), vec![Some(40), Some(40)]);
+
+        let mut results = haps.iter().map(|x| hap_result(x.to_vec())).collect::<Vec<_>>();
+        let posteriors = all_hap_posteriors(&frags, &results);
+        compute_hapqs(&mut results, &posteriors, &graph, 0.9);
+        assert_eq!(results.iter().map(|x| x.hapq).collect::<Vec<_>>(), vec![Some(0), Some(40), Some(0)]);
+
+        //A haplotype with no unitig in the graph has no graph support.
+        let graph = VarmerGraph::from_nodes(std::iter::once((hap_a.as_slice(), 20)));
+        let mut results = vec![hap_result(hap_a.clone()), hap_result(hap_b.clone())];
+        let posteriors = all_hap_posteriors(&frags, &results);
+        compute_hapqs(&mut results, &posteriors, &graph, 0.9);
+        assert_eq!(results.iter().map(|x| x.hapq).collect::<Vec<_>>(), vec![Some(40), Some(0)]);
+    }
+
+    #[test]
+    fn em_recovers_mixing_proportions_with_ambiguous_reads() {
+        //The haplotypes differ at SNPs 6-10 only.
+        let hap_a = (1..=10).map(|pos| (pos, 0)).collect::<Vec<_>>();
+        let hap_b = (1..=10).map(|pos| (pos, (pos > 5) as Genotype)).collect::<Vec<_>>();
+        let mut frags = vec![];
+        for i in 0..30 {
+            frags.push(frag_dbg(frags.len(), hap_a.clone()));
+            //Reads on SNPs 1-5 fit both haplotypes equally well.
+            frags.push(frag_dbg(frags.len(), hap_a[..5].to_vec()));
+            if i < 10 {
+                frags.push(frag_dbg(frags.len(), hap_b.clone()));
+                frags.push(frag_dbg(frags.len(), hap_b[..5].to_vec()));
+            }
+        }
+        let mut results = vec![hap_result(hap_a), hap_result(hap_b)];
+        em_abundances(&mut results, &frags);
+        let abundances = results.iter().map(|x| x.em_abundance.unwrap()).collect::<Vec<_>>();
+        assert!((abundances[0] - 75.).abs() < 0.01, "{:?}", abundances);
+        assert!((abundances[1] - 25.).abs() < 0.01, "{:?}", abundances);
+    }
+
+    #[test]
+    fn count_in_order_matches_sequential_count() {
+        let items = (0..500)
+            .map(|i| (0..20).map(|j| (i * 7 + j * 13) % 37).collect::<Vec<u32>>())
+            .collect::<Vec<_>>();
+        let mut expected: Vec<(u32, u64, usize)> = vec![];
+        for (i, item) in items.iter().enumerate() {
+            for key in item.iter() {
+                match expected.iter_mut().find(|x| x.0 == *key) {
+                    Some(x) => x.1 += 1,
+                    None => expected.push((*key, 1, i)),
+                }
+            }
+        }
+        for threads in [1, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let counts = pool.install(|| count_in_order(&items, |item, keys| keys.extend(item.iter().copied())));
+            assert_eq!(counts, expected);
+        }
+    }
+
+    #[test]
+    fn dbg_node_order_does_not_depend_on_thread_count() {
+        let frags = (0..300)
+            .map(|i| {
+                let first = 1 + i % 40;
+                let seq = (first..first + 12).map(|pos| (pos as SnpPosition, (((i % 23) * 7 + pos * 11) % 5 < 2) as Genotype)).collect();
+                frag_dbg(i, seq)
+            })
+            .collect::<Vec<_>>();
+        let graphs = [1, 3, 8]
+            .iter()
+            .map(|threads| {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(*threads).build().unwrap();
+                let dbg = pool.install(|| dbg_from_frags(&frags, 5, None, None, None, false));
+                let nodes = dbg.ids().map(|x| dbg.node(x).to_vec()).collect::<Vec<_>>();
+                (nodes, dbg.coverage, dbg.out_edges)
+            })
+            .collect::<Vec<_>>();
+        assert!(graphs[0].0.len() > 100, "{}", graphs[0].0.len());
+        assert!(graphs.iter().all(|x| *x == graphs[0]));
+    }
+
+    #[test]
+    fn get_hits_matches_brute_force_comparison() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let frags = (0..200)
+            .map(|_| {
+                let first = rng.gen_range(1..80);
+                let len = rng.gen_range(1..20);
+                let mut seq_vec = vec![];
+                for pos in first..first + len {
+                    if pos == first || rng.gen_bool(0.8) {
+                        seq_vec.push((pos, rng.gen_range(0..2)));
+                    }
+                }
+                dict_frag(seq_vec)
+            })
+            .collect::<Vec<_>>();
+        let index = dict_frag_index(&frags);
+        let (hit_threshold, penalty_allow) = (3, 2);
+
+        let mut num_hits = 0;
+        for query in frags.iter().take(50) {
+            let in_range = |frag: &DictFrag, pos: u32| pos >= frag.first_position && pos <= frag.last_position;
+            let mut expected = vec![];
+            for frag in frags.iter() {
+                if frag == query || frag.last_position < query.first_position || frag.first_position > query.last_position {
+                    continue;
+                }
+                let mut sites: [FxHashSet<u32>; 4] = Default::default();
+                for (pos, geno) in query.seq_vec.iter() {
+                    match frag.seq.get(pos) {
+                        Some(x) if x == geno => sites[0].insert(*pos),
+                        Some(_) if *geno == 0 => sites[1].insert(*pos),
+                        Some(_) => sites[2].insert(*pos),
+                        None if in_range(frag, *pos) => sites[3].insert(*pos),
+                        None => false,
+                    };
+                }
+                for (pos, _) in frag.seq_vec.iter() {
+                    if !query.seq.contains_key(pos) && in_range(query, *pos) {
+                        sites[3].insert(*pos);
+                    }
+                }
+                let mismatches = sites[1].len() + sites[2].len();
+                if mismatches + sites[3].len() > hit_threshold || sites[0].len() + penalty_allow <= mismatches {
+                    continue;
+                }
+                expected.push((frag, sites));
+            }
+            expected.sort_by_key(|x| (x.0.first_position, x.0.last_position));
+
+            let hits = get_hits(query, &frags, &index, hit_threshold, penalty_allow, false);
+            assert_eq!(hits.len(), expected.len());
+            num_hits += hits.len();
+            for (hit, (frag, sites)) in hits.iter().zip(expected.iter()) {
+                assert!(std::ptr::eq(hit.varmer, *frag));
+                assert_eq!([&hit.same, &hit.r_to_a, &hit.a_to_r, &hit.del], [&sites[0], &sites[1], &sites[2], &sites[3]]);
+            }
+        }
+        assert!(num_hits > 0);
+    }
+
+    #[test]
+    fn clean_hanging_kmers_collapses_unitigs_cut_to_the_same_varmer() {
+        //Two source unitigs that differ only in their last k_r SNPs are cut to the same varmer.
+        let varmers: Vec<Vec<(SnpPosition, Genotype)>> = vec![
+            vec![(1, 1), (2, 0), (3, 0)],
+            vec![(1, 1), (2, 1), (3, 1)],
+            vec![(2, 0), (3, 0), (4, 0)],
+            vec![(2, 1), (3, 1), (4, 1)],
+        ];
+        let mut unitigs = VarmerGraph::from_nodes(varmers.iter().map(|x| x.as_slice()).zip([5, 9, 4, 7]));
+        unitigs.add_edge(0, 2);
+        unitigs.add_edge(1, 3);
+
+        let cleaned = clean_hanging_kmers(&unitigs, 3);
+        assert_eq!(cleaned.len(), 3);
+        let id = |varmer: &[(SnpPosition, Genotype)]| cleaned.ids().find(|x| cleaned.node(*x) == varmer).unwrap();
+        let collapsed = id(&[(1, 1)]) as usize;
+        let sinks = [id(&[(3, 0), (4, 0)]), id(&[(3, 1), (4, 1)])];
+        //The collapsed node takes the coverage and out-edges of the last of its unitigs in hash
+        //order; the in-edges of both sinks point to it.
+        let last = (0..unitigs.len()).collect::<FxHashSet<usize>>().into_iter().filter(|x| *x < 2).last().unwrap();
+        assert_eq!(cleaned.coverage[collapsed], [5, 9][last]);
+        assert_eq!(cleaned.out_edges[collapsed], vec![sinks[last]]);
+        for sink in sinks {
+            assert_eq!(cleaned.in_edges[sink as usize], vec![collapsed as NodeId]);
+        }
+    }
+
+    #[test]
+    fn grown_gapped_varmers_do_not_join_sites_around_a_gap() {
+        //Half of the reads have no allele for SNP 3.
+        let mut frags = vec![];
+        for i in 0..10 {
+            let seq = (1..=6).filter(|pos| i % 2 == 0 || *pos != 3).map(|pos| (pos, 0)).collect();
+            frags.push(frag_dbg(i, seq));
+        }
+        let dbg = dbg_from_frags(&frags, 1, None, None, None, true);
+        //A step larger than the previous k, as grow_k takes for a large max_k.
+        let grown = dbg_from_frags(&frags, 3, Some(&dbg), None, Some(2), true);
+        assert_eq!(grown.len(), 4);
+        for node in grown.ids() {
+            assert!(grown.node(node).windows(2).all(|x| x[1].0 == x[0].0 + 1), "{:?}", grown.node(node));
+        }
+    }
 }
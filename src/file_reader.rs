@@ -3,15 +3,17 @@ use log::*;
 use std::ffi::OsString;
 use std::process::Command;
 use crate::constants;
+use crate::utils_frags;
 use crate::parse_cmd_line::Options;
 use crate::types_structs::{
-    build_frag, Frag, Genotype, GnPosition, SnpPosition, VcfProfile,
+    build_frag, Allele, Frag, Genotype, GnPosition, SnpPosition, VcfProfile,
 };
 use debruijn::dna_string::DnaString;
 use fxhash::{FxHashMap, FxHashSet};
 use rayon::prelude::*;
 use rust_htslib::bam::ext::BamRecordExtensions;
 use rust_htslib::bam::IndexedReader;
+use rust_htslib::bam::record::Aux;
 use bio::io::fasta::IndexedReader as FastaIndexedReader;
 use rust_htslib::{bam, bam::Read as DUMMY_NAME1};
 use rust_htslib::{bcf, bcf::Read as DUMMY_NAME2};
@@ -19,7 +21,7 @@ use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 use std::str;
-use std::sync::Mutex;
+use std::sync::mpsc::sync_channel;
 
 // The output is wrapped in a Result to allow matching on errors
 // returns an Iterator to the Reader of the lines of the file.
@@ -85,6 +87,7 @@ where
                         positions: seqs.keys().map(|x| *x).collect::<FxHashSet<SnpPosition>>(),
                         seq_dict: seqs,
                         qual_dict: quals,
+                        allele_lls: FxHashMap::default(),
                         first_position: first_position,
                         last_position: last_position,
                         seq_string: vec![DnaString::new(); 2],
@@ -94,6 +97,8 @@ where
                         first_pos_base: GnPosition::MAX,
                         last_pos_base: GnPosition::MAX,
                         forward_strand: true,
+                        source: 0,
+                        hardclips: (0, 0),
                     };
 
                     all_frags.push(new_frag);
@@ -142,30 +147,12 @@ where
 
     for rec in vcf.records() {
         let unr = rec.unwrap();
-        let alleles = unr.alleles();
-        let mut is_snp = true;
         let record_rid = unr.rid().unwrap();
         let ref_chrom_vcf = header.rid2name(record_rid).unwrap();
         if last_ref_chrom != ref_chrom_vcf {
             last_ref_chrom = ref_chrom_vcf;
         }
 
-        for allele in alleles.iter() {
-            if allele.len() > 1 {
-                is_snp = false;
-                break;
-            }
-        }
-
-        if !is_snp {
-            //            println!(
-            //                "VCF : Variant at position {} is not a snp. Ignoring.",
-            //                unr.pos()
-            //            );
-            continue;
-        }
-
-
         let positions_vec = map_positions_vec
             .entry(String::from_utf8(ref_chrom_vcf.to_vec()).unwrap())
             .or_insert(Vec::new());
@@ -177,7 +164,7 @@ where
 
 
 
-fn alignment_passed_check(
+pub fn alignment_passed_check(
     flags: u16,
     mapq: u8,
     use_supplementary: bool,
@@ -231,30 +218,210 @@ fn alignment_passed_check(
 
 
 
-pub fn get_vcf_profile<'a>(vcf_file: &str, ref_chroms: &'a Vec<String>) -> VcfProfile<'a> {
+#[derive(Clone, Copy)]
+enum VcfSiteFilter {
+    Pass,
+    Qual,
+    Depth,
+    AlleleFreq,
+    Sample,
+}
+
+const NUM_VCF_SITE_FILTERS: usize = 5;
+
+//Returns the first --vcf-* filter the record fails. A filter is not applied if the
+//record lacks the field it needs.
+fn vcf_site_filter(
+    rec: &bcf::Record,
+    options: &Options,
+    sample: Option<usize>,
+    require_sample_alt: bool,
+) -> Option<VcfSiteFilter> {
+    if options.vcf_pass_only && !rec.has_filter("PASS".as_bytes()) {
+        return Some(VcfSiteFilter::Pass);
+    }
+
+    if let Some(min_qual) = options.vcf_min_qual {
+        let qual = rec.qual();
+        if !qual.is_nan() && qual < min_qual {
+            return Some(VcfSiteFilter::Qual);
+        }
+    }
+
+    if let Some(min_dp) = options.vcf_min_dp {
+        if let Some(dp) = vcf_record_depth(rec, sample) {
+            if dp < min_dp {
+                return Some(VcfSiteFilter::Depth);
+            }
+        }
+    }
+
+    if options.vcf_min_af.is_some() || options.vcf_max_af.is_some() {
+        if let Some(af) = vcf_record_alt_af(rec, sample) {
+            if af < options.vcf_min_af.unwrap_or(0.) || af > options.vcf_max_af.unwrap_or(1.) {
+                return Some(VcfSiteFilter::AlleleFreq);
+            }
+        }
+    }
+
+    if require_sample_alt {
+        if let Ok(genotypes) = rec.genotypes() {
+            let gt = genotypes.get(sample.unwrap());
+            if !gt.iter().any(|x| x.index().unwrap_or(0) > 0) {
+                return Some(VcfSiteFilter::Sample);
+            }
+        }
+    }
+
+    None
+}
+
+fn vcf_record_depth(rec: &bcf::Record, sample: Option<usize>) -> Option<i32> {
+    if let Some(s) = sample {
+        if let Ok(dp) = rec.format(b"DP").integer() {
+            if let Some(&d) = dp[s].first() {
+                if d >= 0 {
+                    return Some(d);
+                }
+            }
+        }
+    }
+    if let Ok(Some(dp)) = rec.info(b"DP").integer() {
+        if let Some(&d) = dp.first() {
+            if d >= 0 {
+                return Some(d);
+            }
+        }
+    }
+    None
+}
+
+//Summed frequency of all alternate alleles.
+fn vcf_record_alt_af(rec: &bcf::Record, sample: Option<usize>) -> Option<f64> {
+    if let Some(s) = sample {
+        if let Ok(af) = rec.format(b"AF").float() {
+            let afs = af[s].iter().filter(|x| !x.is_nan()).collect::<Vec<_>>();
+            if !afs.is_empty() {
+                return Some(afs.into_iter().map(|x| *x as f64).sum());
+            }
+        }
+        if let Ok(ad) = rec.format(b"AD").integer() {
+            let ads = ad[s].iter().filter(|x| **x >= 0).collect::<Vec<_>>();
+            let total: i32 = ads.iter().copied().sum();
+            if ads.len() > 1 && total > 0 {
+                let alt: i32 = ads[1..].iter().copied().sum();
+                return Some(alt as f64 / total as f64);
+            }
+        }
+    }
+    if let Ok(Some(af)) = rec.info(b"AF").float() {
+        let afs = af.iter().filter(|x| !x.is_nan()).collect::<Vec<_>>();
+        if !afs.is_empty() {
+            return Some(afs.into_iter().map(|x| *x as f64).sum());
+        }
+    }
+    None
+}
+
+//Merge the regions to phase into sorted, non-overlapping 0-based inclusive intervals for
+//each contig. None means the whole contig is phased.
+fn merge_regions_per_contig(
+    regions: &[(String, Option<(usize, usize)>)],
+) -> FxHashMap<&str, Option<Vec<(u64, u64)>>> {
+    let mut contig_regions: FxHashMap<&str, Option<Vec<(u64, u64)>>> = FxHashMap::default();
+    for (contig, range) in regions.iter() {
+        let entry = contig_regions.entry(contig.as_str()).or_insert(Some(vec![]));
+        match range {
+            None => *entry = None,
+            Some((start, end)) => {
+                if let Some(intervals) = entry {
+                    intervals.push((*start as u64, *end as u64));
+                }
+            }
+        }
+    }
+
+    for intervals in contig_regions.values_mut().flatten() {
+        intervals.sort();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for &(start, end) in intervals.iter() {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1 + 1 {
+                    last.1 = last.1.max(end);
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        *intervals = merged;
+    }
+    contig_regions
+}
+
+//Reads variants for the contigs in ref_chroms. If regions are given and the VCF/BCF is indexed (tbi
+//or csi), only records overlapping the regions are fetched; otherwise the whole file is streamed
+//and records outside the regions are skipped.
+pub fn get_vcf_profile<'a>(
+    vcf_file: &str,
+    ref_chroms: &'a [String],
+    regions: &[(String, Option<(usize, usize)>)],
+    options: &Options,
+) -> VcfProfile<'a> {
     let mut vcf_prof = VcfProfile::default();
-    let mut vcf = match bcf::Reader::from_path(vcf_file) {
-        Ok(vcf) => vcf,
-        Err(_) =>{ error!("rust_htslib had an error while reading the VCF file. Exiting."); std::process::exit(1)},
-    };
-    let mut snp_counter = 1;
-    let mut vcf_pos_allele_map = FxHashMap::default();
-    let mut vcf_pos_to_snp_counter_map = FxHashMap::default();
-    let mut vcf_snp_pos_to_gn_pos_map = FxHashMap::default();
+    let contig_regions = merge_regions_per_contig(regions);
+
+    let mut indexed_vcf = None;
+    let mut streamed_vcf = None;
+    let has_index = Path::new(&format!("{}.tbi", vcf_file)).exists()
+        || Path::new(&format!("{}.csi", vcf_file)).exists();
+    if !contig_regions.is_empty() && has_index {
+        match bcf::IndexedReader::from_path(vcf_file) {
+            Ok(vcf) => indexed_vcf = Some(vcf),
+            Err(_) => log::warn!("Could not open the VCF index for {}. Reading the whole file.", vcf_file),
+        }
+    } else if !contig_regions.is_empty() {
+        log::debug!("No .tbi or .csi index found for {}. Reading the whole file.", vcf_file);
+    }
+    if indexed_vcf.is_none() {
+        streamed_vcf = match bcf::Reader::from_path(vcf_file) {
+            Ok(vcf) => Some(vcf),
+            Err(_) =>{ error!("rust_htslib had an error while reading the VCF file. Exiting."); std::process::exit(1)},
+        };
+    }
+
     let mut chrom_to_index_map = FxHashMap::default();
     for (i, chrom) in ref_chroms.iter().enumerate() {
         chrom_to_index_map.insert(chrom.as_bytes(), i);
     }
 
-    let vcf_header = vcf.header().clone();
-    let mut curr_pos = 0;
+    let vcf_header = match &indexed_vcf {
+        Some(vcf) => vcf.header().clone(),
+        None => streamed_vcf.as_ref().unwrap().header().clone(),
+    };
 
-    let mut last_ref_chrom = &String::default();
-    for rec in vcf.records() {
-        let unr = rec.unwrap();
+    //Per-sample fields come from --vcf-sample, or the only sample of a single-sample VCF.
+    let sample;
+    if let Some(sample_name) = &options.vcf_sample {
+        //sample_id is not safe to call on a header without samples.
+        let id = if vcf_header.sample_count() > 0 { vcf_header.sample_id(sample_name.as_bytes()) } else { None };
+        sample = match id {
+            Some(id) => Some(id),
+            None => {
+                error!("Sample {} not found in the VCF file. Exiting.", sample_name);
+                std::process::exit(1);
+            }
+        };
+    } else if vcf_header.sample_count() == 1 {
+        sample = Some(0);
+    } else {
+        sample = None;
+    }
+    let mut filter_counts = [0; NUM_VCF_SITE_FILTERS];
+
+    let mut add_record = |unr: bcf::Record| {
         let alleles = unr.alleles();
         let mut al_vec = Vec::new();
-        let mut is_snp = true;
+        let mut is_valid = true;
 
         let record_rid = unr.rid().unwrap();
         let ref_chrom_vcf =
@@ -262,81 +429,158 @@ pub fn get_vcf_profile<'a>(vcf_file: &str, ref_chroms: &'a Vec<String>) -> VcfPr
             vcf_header.rid2name(record_rid).unwrap();
         let result = chrom_to_index_map.get(&ref_chrom_vcf);
         if result.is_none() {
-            continue;
+            return;
         }
         let contig_name = &ref_chroms[*result.unwrap()];
-        //dbg!(String::from_utf8_lossy(ref_chrom_vcf));
-        if last_ref_chrom != contig_name {
-            snp_counter = 1;
-            last_ref_chrom = contig_name;
-            curr_pos = 0;
-        }
-        let pos_allele_map : &mut FxHashMap<GnPosition, Vec<Genotype>>
-            = vcf_pos_allele_map
-            .entry(contig_name.as_str())
-            .or_insert(FxHashMap::default());
-        let pos_to_snp_counter_map = vcf_pos_to_snp_counter_map
-            .entry(contig_name.as_str())
-            .or_insert(FxHashMap::default());
-        let snp_pos_to_gn_pos_map = vcf_snp_pos_to_gn_pos_map
-            .entry(contig_name.as_str())
-            .or_insert(vec![]);
+
+        if let Some(filter) = vcf_site_filter(&unr, options, sample, options.vcf_sample.is_some()) {
+            filter_counts[filter as usize] += 1;
+            return;
+        }
 
         for allele in alleles.iter() {
-            if allele.len() > 1 {
-                is_snp = false;
+            //Symbolic alleles (<DEL>, *, etc.) can not be genotyped from the CIGAR.
+            if allele.is_empty() || allele.iter().any(|x| !b"ACGTNacgtn".contains(x)) {
+                is_valid = false;
                 break;
             }
-            al_vec.push(allele[0] as Genotype);
+            al_vec.push(allele.to_ascii_uppercase());
         }
 
-        if !is_snp {
-            continue;
+        if !is_valid {
+            return;
         }
 
-        if curr_pos != 0 && unr.pos() == curr_pos{
-            debug!("VCF : Variant at position {} is too close to previous variant. Ignoring.", unr.pos());
-            let vec = pos_allele_map.get_mut(&(curr_pos as GnPosition)).unwrap();
-            for allele in al_vec.iter(){
-                if !vec.contains(allele){
-                    vec.push(*allele);
+        vcf_prof.add_variant(contig_name.as_str(), unr.pos() as GnPosition, al_vec);
+    };
+
+    if let Some(vcf) = indexed_vcf.as_mut() {
+        for contig in ref_chroms.iter() {
+            let intervals = match contig_regions.get(contig.as_str()) {
+                Some(intervals) => intervals,
+                None => continue,
+            };
+            let rid = match vcf_header.name2rid(contig.as_bytes()) {
+                Ok(rid) => rid,
+                Err(_) => continue,
+            };
+            //Contigs without records are missing from tabix indices.
+            match intervals {
+                None => {
+                    if vcf.fetch(rid, 0, None).is_err() {
+                        log::debug!("Contig {} not in the index of {}.", contig, vcf_file);
+                        continue;
+                    }
+                    for rec in vcf.records() {
+                        add_record(rec.unwrap());
+                    }
+                }
+                Some(intervals) => {
+                    //A record overlapping two intervals is fetched twice; only keep it the first time.
+                    let mut prev_end = None;
+                    for &(start, end) in intervals.iter() {
+                        if vcf.fetch(rid, start, Some(end)).is_err() {
+                            log::debug!("Contig {} not in the index of {}.", contig, vcf_file);
+                            break;
+                        }
+                        for rec in vcf.records() {
+                            let unr = rec.unwrap();
+                            if prev_end.is_some() && unr.pos() as u64 <= prev_end.unwrap() {
+                                continue;
+                            }
+                            add_record(unr);
+                        }
+                        prev_end = Some(end);
+                    }
                 }
             }
-            continue;
         }
-        curr_pos = unr.pos();
-        snp_pos_to_gn_pos_map.push(unr.pos() as GnPosition);
-        pos_to_snp_counter_map.insert(unr.pos() as GnPosition, snp_counter - 1);
-        snp_counter += 1;
-        pos_allele_map.insert(unr.pos() as GnPosition, al_vec);
+    } else {
+        for rec in streamed_vcf.as_mut().unwrap().records() {
+            let unr = rec.unwrap();
+            if !contig_regions.is_empty() {
+                let contig = vcf_header.rid2name(unr.rid().unwrap()).unwrap();
+                let in_regions = match contig_regions.get(str::from_utf8(contig).unwrap()) {
+                    Some(None) => true,
+                    Some(Some(intervals)) => intervals
+                        .iter()
+                        .any(|&(start, end)| unr.pos() as u64 <= end && unr.end() as u64 > start),
+                    None => false,
+                };
+                if !in_regions {
+                    continue;
+                }
+            }
+            add_record(unr);
+        }
+    }
+
+    if options.vcf_pass_only {
+        log::info!("{} VCF records removed by --vcf-pass-only.", filter_counts[VcfSiteFilter::Pass as usize]);
+    }
+    if options.vcf_min_qual.is_some() {
+        log::info!("{} VCF records removed by --vcf-min-qual.", filter_counts[VcfSiteFilter::Qual as usize]);
+    }
+    if options.vcf_min_dp.is_some() {
+        log::info!("{} VCF records removed by --vcf-min-dp.", filter_counts[VcfSiteFilter::Depth as usize]);
+    }
+    if options.vcf_min_af.is_some() || options.vcf_max_af.is_some() {
+        log::info!("{} VCF records removed by --vcf-min-af/--vcf-max-af.", filter_counts[VcfSiteFilter::AlleleFreq as usize]);
+    }
+    if options.vcf_sample.is_some() {
+        log::info!("{} VCF records removed by --vcf-sample (no non-reference genotype).", filter_counts[VcfSiteFilter::Sample as usize]);
     }
 
-    vcf_prof.vcf_pos_allele_map = vcf_pos_allele_map;
-    vcf_prof.vcf_pos_to_snp_counter_map = vcf_pos_to_snp_counter_map;
-    vcf_prof.vcf_snp_pos_to_gn_pos_map = vcf_snp_pos_to_gn_pos_map;
     return vcf_prof;
 }
 
 pub fn get_bam_readers(
     options: &Options,
-) -> bam::IndexedReader{
-    let long_bam_file = &options.bam_file;
-    let long_bam = match bam::IndexedReader::from_path(long_bam_file) {
-        Ok(long_bam) => long_bam,
-        Err(_) =>{ error!("rust_htslib had an error while reading BAM file. Exiting");std::process::exit(1)},
-    };
+) -> Vec<bam::IndexedReader>{
+    let mut long_bams = vec![];
+    for long_bam_file in options.bam_file.iter() {
+        let mut long_bam = match bam::IndexedReader::from_path(long_bam_file) {
+            Ok(long_bam) => long_bam,
+            Err(_) =>{ error!("rust_htslib had an error while reading BAM/CRAM file {}. Exiting", long_bam_file);std::process::exit(1)},
+        };
+
+        //CRAM records are decoded against the reference. This does nothing for BAM files.
+        if !options.reference_fasta.is_empty() && long_bam.set_reference(&options.reference_fasta).is_err() {
+            error!("Could not set reference {} for decoding {}. Exiting", &options.reference_fasta, long_bam_file);
+            std::process::exit(1);
+        }
+        long_bams.push(long_bam);
+    }
 
-    return long_bam;
+    long_bams
 }
 
+//Value of a SAM tag (e.g. RG, BC, CB) used to split reads into groups.
+fn record_group(record: &bam::Record, tag: &str) -> Option<String> {
+    match record.aux(tag.as_bytes()) {
+        Ok(Aux::String(s)) => Some(s.to_string()),
+        Ok(Aux::Char(c)) => Some((c as char).to_string()),
+        Ok(Aux::I8(v)) => Some(v.to_string()),
+        Ok(Aux::U8(v)) => Some(v.to_string()),
+        Ok(Aux::I16(v)) => Some(v.to_string()),
+        Ok(Aux::U16(v)) => Some(v.to_string()),
+        Ok(Aux::I32(v)) => Some(v.to_string()),
+        Ok(Aux::U32(v)) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+//Returns (group, fragments with SNPs, fragments without SNPs) for each group of reads sorted
+//by group name. Without a split tag all reads are in one group named "".
 pub fn get_frags_from_bamvcf_rewrite(
-    main_bam: &mut bam::IndexedReader,
+    main_bams: &mut [bam::IndexedReader],
     vcf_profile: &VcfProfile,
     options: &Options,
     chrom_seqs: &mut Option<FastaIndexedReader<std::fs::File>>,
     contig: &str,
     range: Option<(usize, usize)>,
-) -> (Vec<Frag>, Vec<Frag>)
+    split_tag: Option<&str>,
+) -> Vec<(String, Vec<Frag>, Vec<Frag>)>
 {
 
     let filter_supplementary = true;
@@ -345,119 +589,182 @@ pub fn get_frags_from_bamvcf_rewrite(
     let vcf_pos_to_snp_counter_map = &vcf_profile.vcf_pos_to_snp_counter_map;
     let vcf_snp_pos_to_gn_pos_map = &vcf_profile.vcf_snp_pos_to_gn_pos_map;
 
-    let long_bam = main_bam;
-
-    if range.is_some() {
-        long_bam.fetch((contig, range.unwrap().0 as i32, range.unwrap().1 as i32)).unwrap();
-    } else {
-        long_bam.fetch(contig).unwrap();
-    }
-
-    let mut record_vec = vec![];
-    for record in long_bam.records() {
-        if record.is_ok() {
-            record_vec.push(record.unwrap());
-        }
-    }
-
     let mut seq = Vec::new(); 
     if chrom_seqs.is_some(){
         chrom_seqs.as_mut().unwrap().fetch_all(contig).expect("Error reading fasta file.");
         chrom_seqs.as_mut().unwrap().read(&mut seq).expect("Error reading fasta file.");
     }
-
-    let ref_id_to_frag_map: Mutex<FxHashMap<_, _>> = Mutex::new(FxHashMap::default());
-    let rec_vecs = vec![record_vec];
-    log::info!("Number of records in BAM file for contig: {}", rec_vecs[0].len());
-    for record_vec in rec_vecs {
-        record_vec
-            .into_par_iter()
-            //            .into_iter()
-            .enumerate()
-            .for_each(|(count, record)| {
-                if record.tid() < 0 {
+    let chrom_seqs = &*chrom_seqs;
+
+    let mut ref_id_to_frag_map = FxHashMap::default();
+    let mut num_records = 0;
+    let mut untagged_count = 0;
+
+    //Records are read in chunks on another thread while the previous chunk is turned into
+    //fragments, so only a few chunks of records are held at once. Records of all input files
+    //are pooled; each keeps the index of its file. The fragment counter_id is the index of the
+    //record over all chunks.
+    std::thread::scope(|scope| {
+        let (sender, receiver) = sync_channel::<(usize, Vec<(usize, String, bam::Record)>)>(
+            constants::RECORD_CHUNKS_IN_FLIGHT,
+        );
+        let main_bams = &mut *main_bams;
+        let producer = scope.spawn(move || {
+            let mut num_records = 0;
+            let mut untagged_count = 0;
+            let mut chunk = Vec::with_capacity(constants::RECORD_CHUNK_SIZE);
+            let mut record = bam::Record::new();
+            for (source, long_bam) in main_bams.iter_mut().enumerate() {
+                let fetched = if let Some((s, e)) = range {
+                    long_bam.fetch((contig, s as i32, e as i32))
                 } else {
-                    let passed_check = alignment_passed_check(
-                        record.flags(),
-                        record.mapq(),
-                        use_supplementary,
-                        filter_supplementary,
-                        options.mapq_cutoff,
-                    );
-
-                    //                    log::trace!(
-                    //                        "{},{:?}",
-                    //                        str::from_utf8(&record.qname()).unwrap(),
-                    //                        passed_check
-                    //                    );
-
-                    if passed_check.0 {
-                        //get start and end of alignment
-
-                        let aln_start = record.reference_start();
-                        let aln_end = record.reference_end();
-                        if let Some(range) = range {
-                            //Overlap the range
-                            if aln_start > range.1 as i64 || aln_end < range.0 as i64 {
-                                log::trace!(
-                                    "Read {} does not overlap with range {:?}. Skipping.",
-                                    str::from_utf8(&record.qname()).unwrap(),
-                                    range
-                                );
-                                return;
+                    long_bam.fetch(contig)
+                };
+                if fetched.is_err() {
+                    log::debug!("Contig {} not found in {}", contig, options.bam_file[source]);
+                    continue;
+                }
+
+                while let Some(result) = long_bam.read(&mut record) {
+                    if result.is_err() {
+                        continue;
+                    }
+                    let group = match split_tag {
+                        Some(tag) => match record_group(&record, tag) {
+                            Some(group) => group,
+                            None => {
+                                untagged_count += 1;
+                                continue;
                             }
-                        }
-                        let rec_name: Vec<u8> = record.qname().iter().cloned().collect();
-                        let snp_positions_contig = &vcf_pos_to_snp_counter_map[contig];
-                        let pos_allele_map = &vcf_pos_allele_map[contig];
-                        let snp_to_gn_map = &vcf_snp_pos_to_gn_pos_map[contig];
-                        //                        if str::from_utf8(&record.qname()) == Ok("pa1_4940"){
-                        //                            dbg!(record.flags(),record.mapq(), passed_check, str::from_utf8(&rec_name));
-                        //                        }
-                        let mut frag =
-                            frag_from_record(&record, snp_positions_contig, pos_allele_map, count);
-
-//                        if frag.seq_dict.keys().len() > 0 {
-                        if !chrom_seqs.is_none() && !options.no_realign {
-                            alignment::realign(
-                                &seq,
-                                &mut frag,
-                                &snp_to_gn_map,
-                                &pos_allele_map,
-                            );
-                        }
-                        let mut locked = ref_id_to_frag_map.lock().unwrap();
-                        let bucket = locked.entry(rec_name).or_insert(vec![]);
-                        bucket.push((record.flags(), frag));
-//                        }
+                        },
+                        None => String::new(),
+                    };
+                    //Cloned records do not share the reader's header, so they can be sent
+                    //to other threads.
+                    chunk.push((source, group, record.clone()));
+                    if chunk.len() == constants::RECORD_CHUNK_SIZE {
+                        let full_chunk = std::mem::replace(&mut chunk, Vec::with_capacity(constants::RECORD_CHUNK_SIZE));
+                        sender.send((num_records, full_chunk)).unwrap();
+                        num_records += constants::RECORD_CHUNK_SIZE;
                     }
                 }
-            });
-    }
-
-    let ref_vec_frags = combine_frags(
-        ref_id_to_frag_map.into_inner().unwrap(),
-        &vcf_profile,
-        contig,
-        options,
-    );
+            }
+            let last_len = chunk.len();
+            if last_len > 0 {
+                sender.send((num_records, chunk)).unwrap();
+                num_records += last_len;
+            }
+            (num_records, untagged_count)
+        });
+
+        for (offset, chunk) in receiver {
+            let mut chunk_frags = chunk
+                .into_par_iter()
+                .enumerate()
+                .filter_map(|(i, (source, group, record))| {
+                    if record.tid() < 0 {
+                        None
+                    } else {
+                        let passed_check = alignment_passed_check(
+                            record.flags(),
+                            record.mapq(),
+                            use_supplementary,
+                            filter_supplementary,
+                            options.mapq_cutoff,
+                        );
+
+                        //                    log::trace!(
+                        //                        "{},{:?}",
+                        //                        str::from_utf8(&record.qname()).unwrap(),
+                        //                        passed_check
+                        //                    );
+
+                        if passed_check.0 {
+                            //get start and end of alignment
+
+                            let aln_start = record.reference_start();
+                            let aln_end = record.reference_end();
+                            if let Some(range) = range {
+                                //Overlap the range
+                                if aln_start > range.1 as i64 || aln_end < range.0 as i64 {
+                                    log::trace!(
+                                        "Read {} does not overlap with range {:?}. Skipping.",
+                                        str::from_utf8(record.qname()).unwrap(),
+                                        range
+                                    );
+                                    return None;
+                                }
+                            }
+                            let rec_name: Vec<u8> = record.qname().to_vec();
+                            let snp_positions_contig = &vcf_pos_to_snp_counter_map[contig];
+                            let pos_allele_map = &vcf_pos_allele_map[contig];
+                            //                        if str::from_utf8(&record.qname()) == Ok("pa1_4940"){
+                            //                            dbg!(record.flags(),record.mapq(), passed_check, str::from_utf8(&rec_name));
+                            //                        }
+                            let mut frag =
+                                frag_from_record(&record, snp_positions_contig, pos_allele_map, offset + i);
+                            frag.source = source;
+                            Some((group, source, rec_name, record.flags(), frag))
+                        } else {
+                            None
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            if !chrom_seqs.is_none() && !options.no_realign {
+                let mut frags = chunk_frags.iter_mut().map(|x| &mut x.4).collect::<Vec<_>>();
+                alignment::realign(
+                    &seq,
+                    &mut frags,
+                    &vcf_snp_pos_to_gn_pos_map[contig],
+                    &vcf_pos_allele_map[contig],
+                );
+            }
 
-    //    for frag in ref_vec_frags.iter(){
-    //        if frag.id.contains("485041"){
-    //            dbg!(&frag);
-    //        }
-    //    }
-    let mut frags_with_snps = vec![];
-    let mut frags_without_snps = vec![];
-    for frag in ref_vec_frags{
-        if frag.seq_dict.keys().len() > 0{
-            frags_with_snps.push(frag);
+            for (group, source, rec_name, flags, frag) in chunk_frags {
+                //Reads with the same name in different input files are different reads.
+                let bucket = ref_id_to_frag_map
+                    .entry(group)
+                    .or_insert(FxHashMap::default())
+                    .entry((source, rec_name))
+                    .or_insert(vec![]);
+                bucket.push((flags, frag));
+            }
         }
-        else{
-            frags_without_snps.push(frag);
+        (num_records, untagged_count) = producer.join().unwrap();
+    });
+
+    if let Some(tag) = split_tag {
+        log::debug!("{} records without the {} tag were skipped.", untagged_count, tag);
+    }
+    log::info!("Number of records in BAM file for contig: {}", num_records);
+
+    let mut group_to_frag_map = ref_id_to_frag_map.into_iter().collect::<Vec<_>>();
+    group_to_frag_map.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut group_frags = vec![];
+    for (group, id_to_frag_map) in group_to_frag_map {
+        let ref_vec_frags = combine_frags(
+            id_to_frag_map,
+            vcf_profile,
+            contig,
+            options,
+        );
+
+        let mut frags_with_snps = vec![];
+        let mut frags_without_snps = vec![];
+        for frag in ref_vec_frags{
+            if frag.seq_dict.keys().len() > 0{
+                frags_with_snps.push(frag);
+            }
+            else{
+                frags_without_snps.push(frag);
+            }
         }
+        group_frags.push((group, frags_with_snps, frags_without_snps));
     }
-    (frags_with_snps, frags_without_snps)
+    group_frags
 }
 
 pub fn get_fasta_seqs(fasta_file: &str) -> FastaIndexedReader<std::fs::File> {
@@ -487,8 +794,11 @@ pub fn get_fasta_seqs(fasta_file: &str) -> FastaIndexedReader<std::fs::File> {
     }
 }
 
+//Alignments of each read with their SAM flags, keyed by (input file index, read name).
+type ReadAlignments = FxHashMap<(usize, Vec<u8>), Vec<(u16, Frag)>>;
+
 fn combine_frags(
-    id_to_frag_map: FxHashMap<Vec<u8>, Vec<(u16, Frag)>>,
+    id_to_frag_map: ReadAlignments,
     vcf_profile: &VcfProfile,
     contig: &str,
     options: &Options
@@ -529,6 +839,7 @@ fn combine_frags(
 
             first_frag.seq_dict.extend(sec_frag.seq_dict);
             first_frag.qual_dict.extend(sec_frag.qual_dict);
+            first_frag.allele_lls.extend(sec_frag.allele_lls);
             first_frag.positions.extend(sec_frag.positions);
 
             first_frag.first_position =
@@ -625,9 +936,11 @@ fn combine_frags(
                     if i == primary_alignment_index.unwrap() {
                         continue;
                     }
-                    let frag = std::mem::take(&mut frags[i].1);
+                    let mut frag = std::mem::take(&mut frags[i].1);
+                    supp_to_primary_seq_pos(&primary_frag, &mut frag);
                     primary_frag.seq_dict.extend(frag.seq_dict);
                     primary_frag.qual_dict.extend(frag.qual_dict);
+                    primary_frag.allele_lls.extend(frag.allele_lls);
                     primary_frag.positions.extend(frag.positions);
 
                     primary_frag.first_position =
@@ -650,17 +963,53 @@ fn combine_frags(
     return ref_frags;
 }
 
+//Supplementary alignments are hard clipped and may be on the other strand, so their read
+//positions do not index the primary alignment's sequence. Map them onto it through the
+//position in the original read. Sites whose read base is hard clipped in the primary alignment
+//are dropped from the supplementary fragment.
+fn supp_to_primary_seq_pos(primary_frag: &Frag, supp_frag: &mut Frag) {
+    let supp_len = supp_frag.seq_string[0].len() + supp_frag.hardclips.0 + supp_frag.hardclips.1;
+    let primary_len = primary_frag.seq_string[0].len();
+    let mut dropped = vec![];
+    for (snp_pos, (_read_pair, seq_pos)) in supp_frag.snp_pos_to_seq_pos.iter_mut() {
+        let mut read_pos = *seq_pos + supp_frag.hardclips.0;
+        if supp_frag.forward_strand != primary_frag.forward_strand {
+            read_pos = supp_len - 1 - read_pos;
+        }
+        match read_pos.checked_sub(primary_frag.hardclips.0) {
+            Some(pos) if pos < primary_len => *seq_pos = pos,
+            _ => dropped.push(*snp_pos),
+        }
+    }
+    if dropped.is_empty() {
+        return;
+    }
+    log::trace!(
+        "{} sites of a supplementary alignment of {} are clipped in the primary alignment",
+        dropped.len(),
+        supp_frag.id
+    );
+    for snp_pos in dropped {
+        supp_frag.snp_pos_to_seq_pos.remove(&snp_pos);
+        supp_frag.seq_dict.remove(&snp_pos);
+        supp_frag.qual_dict.remove(&snp_pos);
+        supp_frag.allele_lls.remove(&snp_pos);
+        supp_frag.positions.remove(&snp_pos);
+    }
+    //An empty fragment leaves the primary's span unchanged when merged.
+    supp_frag.first_position = supp_frag.positions.iter().copied().min().unwrap_or(SnpPosition::MAX);
+    supp_frag.last_position = supp_frag.positions.iter().copied().max().unwrap_or(0);
+}
+
 fn frag_from_record(
     record: &bam::Record,
     snp_positions: &FxHashMap<GnPosition, SnpPosition>,
-    pos_allele_map: &FxHashMap<GnPosition, Vec<Genotype>>,
+    pos_allele_map: &FxHashMap<GnPosition, Vec<Allele>>,
     counter_id: usize,
 ) -> Frag {
     let first_in_pair_mask = 64;
     let second_in_pair_mask = 128;
-    let supplementary_mask = 2048;
     let reverse_mask = 16;
-    let mut _leading_hardclips = 0;
     let paired =
         (record.flags() & first_in_pair_mask > 0) || (record.flags() & second_in_pair_mask > 0);
     let reverse = record.flags() & reverse_mask > 0;
@@ -672,54 +1021,90 @@ fn frag_from_record(
         paired,
     );
     frag.forward_strand = !reverse;
-    if record.flags() & supplementary_mask > 0 {
-        _leading_hardclips = record.cigar().leading_hardclips();
-    }
+    let cigar = record.cigar();
+    frag.hardclips = (
+        cigar.leading_hardclips() as usize,
+        cigar.trailing_hardclips() as usize,
+    );
     frag.first_pos_base = record.reference_start() as GnPosition;
     frag.last_pos_base = record.reference_end() as GnPosition;
 
+    let read_seq = record.seq();
+    let read_qual = record.qual();
+
+    //Indel and MNP sites spanning more than one reference base. Read bases are collected over
+    //the REF span (including inserted bases) before the read allele is compared to the VCF.
+    let mut open_sites: Vec<PendingSite> = vec![];
+
     for pair in aligned_pairs {
         if pair[1].is_none() {
+            //Insertion or soft clip; belongs to any multi-base site still being read.
+            if let Some(seq_pos) = pair[0] {
+                for site in open_sites.iter_mut() {
+                    site.push_base(seq_pos as usize, &read_seq, read_qual);
+                }
+            }
             continue;
         }
         let genome_pos = pair[1].unwrap() as GnPosition;
+
+        //Close all multi-base sites whose REF span ended before this position.
+        if !open_sites.is_empty() {
+            let mut i = 0;
+            while i < open_sites.len() {
+                if open_sites[i].ref_end <= genome_pos {
+                    let site = open_sites.swap_remove(i);
+                    call_multibase_site(&mut frag, site, snp_positions, pos_allele_map);
+                } else {
+                    i += 1;
+                }
+            }
+            if let Some(seq_pos) = pair[0] {
+                for site in open_sites.iter_mut() {
+                    site.push_base(seq_pos as usize, &read_seq, read_qual);
+                }
+            }
+        }
+
         if !snp_positions.contains_key(&genome_pos) {
             if !pair[0].is_none() {
                 _last_read_aligned_pos = pair[0].unwrap() as GnPosition;
             }
             continue;
+        }
+
+        let alleles = pos_allele_map.get(&genome_pos).unwrap();
+        if alleles.iter().any(|x| x.len() != 1) {
+            let mut site = PendingSite {
+                genome_pos,
+                ref_end: genome_pos + alleles[0].len(),
+                first_seq_pos: None,
+                bases: vec![],
+                min_qual: u8::MAX,
+            };
+            if let Some(seq_pos) = pair[0] {
+                site.push_base(seq_pos as usize, &read_seq, read_qual);
+            }
+            open_sites.push(site);
+            continue;
+        }
+
+        //Deletion
+        if pair[0].is_none() {
         } else {
-            //Deletion
-            if pair[0].is_none() {
-            } else {
-                let seq_pos = pair[0].unwrap() as GnPosition;
-                let readbase = record.seq()[seq_pos] as Genotype;
-                for (i, allele) in pos_allele_map
-                    .get(&(genome_pos))
-                    .unwrap()
-                    .iter()
-                    .enumerate()
-                {
-                    if readbase == *allele {
-                        let snp_pos = snp_positions[&genome_pos] as SnpPosition + 1;
-                        frag.seq_dict.insert(snp_pos, i as Genotype);
-                        frag.qual_dict.insert(snp_pos, record.qual()[seq_pos]);
-                        if snp_pos < frag.first_position {
-                            frag.first_position = snp_pos;
-                        }
-                        if snp_pos > frag.last_position {
-                            frag.last_position = snp_pos
-                        }
-                        //Long read assumption.
-                        frag.snp_pos_to_seq_pos
-                            //.insert(snp_pos, (0, seq_pos + leading_hardclips as usize));
-                            .insert(snp_pos, (0, seq_pos as usize));
-                        break;
-                    }
+            let seq_pos = pair[0].unwrap() as GnPosition;
+            let readbase = read_seq[seq_pos] as Genotype;
+            for (i, allele) in alleles.iter().enumerate() {
+                if readbase == allele[0] {
+                    let snp_pos = snp_positions[&genome_pos] as SnpPosition + 1;
+                    add_site_to_frag(&mut frag, snp_pos, i as Genotype, read_qual[seq_pos], seq_pos, alleles.len());
+                    break;
                 }
             }
         }
     }
+    //Sites still open here run into the end of the alignment (or a soft clip), so the read
+    //allele is incomplete and is not called.
 
     frag.seq_string[0] = DnaString::from_acgt_bytes(&record.seq().as_bytes());
     frag.positions = frag
@@ -731,14 +1116,122 @@ fn frag_from_record(
     return frag;
 }
 
-pub fn get_contigs_to_phase(bam_file: &str) -> Vec<String> {
-    let bam = IndexedReader::from_path(bam_file).unwrap();
-    return bam
-        .header()
-        .target_names()
+//Read bases collected over the REF span of an indel/MNP site.
+struct PendingSite {
+    genome_pos: GnPosition,
+    ref_end: GnPosition,
+    first_seq_pos: Option<usize>,
+    bases: Vec<u8>,
+    min_qual: u8,
+}
+
+impl PendingSite {
+    fn push_base(&mut self, seq_pos: usize, read_seq: &bam::record::Seq, read_qual: &[u8]) {
+        if self.first_seq_pos.is_none() {
+            self.first_seq_pos = Some(seq_pos);
+        }
+        self.bases.push(read_seq[seq_pos]);
+        self.min_qual = self.min_qual.min(read_qual[seq_pos]);
+    }
+}
+
+fn call_multibase_site(
+    frag: &mut Frag,
+    site: PendingSite,
+    snp_positions: &FxHashMap<GnPosition, SnpPosition>,
+    pos_allele_map: &FxHashMap<GnPosition, Vec<Allele>>,
+) {
+    //Every base of the site is deleted in the read.
+    if site.first_seq_pos.is_none() {
+        return;
+    }
+    let alleles = &pos_allele_map[&site.genome_pos];
+    let mut geno = alleles.iter().position(|x| *x == site.bases);
+
+    //Noisy reads rarely reproduce inserted bases exactly, but the indel length is usually
+    //right. Call the allele if it is the only one with the observed length whose bases outside
+    //the insertion (relative to REF) match the read; REF and pure deletions need an exact match.
+    if geno.is_none() && alleles.iter().any(|x| x.len() != alleles[0].len()) {
+        let same_length = alleles
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.len() == site.bases.len() && flanks_match(x, &alleles[0], &site.bases))
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        if same_length.len() == 1 {
+            geno = Some(same_length[0]);
+        }
+    }
+
+    if let Some(geno) = geno {
+        let snp_pos = snp_positions[&site.genome_pos] as SnpPosition + 1;
+        add_site_to_frag(
+            frag,
+            snp_pos,
+            geno as Genotype,
+            site.min_qual,
+            site.first_seq_pos.unwrap(),
+            alleles.len(),
+        );
+    }
+}
+
+//Whether read bases of the same length as allele match it outside the bases the allele inserts
+//or replaces relative to ref_allele, i.e. on the prefix and suffix it shares with ref_allele.
+fn flanks_match(allele: &[u8], ref_allele: &[u8], read_bases: &[u8]) -> bool {
+    let prefix = allele
+        .iter()
+        .zip(ref_allele.iter())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let suffix = allele[prefix..]
         .iter()
-        .map(|x| String::from_utf8(x.to_vec()).unwrap())
-        .collect();
+        .rev()
+        .zip(ref_allele[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let inserted = allele.len() - prefix - suffix;
+    inserted > 0
+        && read_bases[..prefix] == allele[..prefix]
+        && read_bases[prefix + inserted..] == allele[prefix + inserted..]
+}
+
+#[inline]
+fn add_site_to_frag(
+    frag: &mut Frag,
+    snp_pos: SnpPosition,
+    geno: Genotype,
+    qual: u8,
+    seq_pos: usize,
+    num_alleles: usize,
+) {
+    frag.seq_dict.insert(snp_pos, geno);
+    frag.qual_dict.insert(snp_pos, qual);
+    frag.allele_lls
+        .insert(snp_pos, utils_frags::qual_allele_lls(geno, qual, num_alleles));
+    if snp_pos < frag.first_position {
+        frag.first_position = snp_pos;
+    }
+    if snp_pos > frag.last_position {
+        frag.last_position = snp_pos
+    }
+    //Long read assumption.
+    frag.snp_pos_to_seq_pos.insert(snp_pos, (0, seq_pos));
+}
+
+//Contigs of all input files, in order of first appearance.
+pub fn get_contigs_to_phase(options: &Options) -> Vec<String> {
+    let mut contigs = vec![];
+    let mut seen = FxHashSet::default();
+    for bam in get_bam_readers(options) {
+        for name in bam.header().target_names() {
+            let name = String::from_utf8(name.to_vec()).unwrap();
+            if seen.insert(name.clone()) {
+                contigs.push(name);
+            }
+        }
+    }
+    contigs
 }
 
 
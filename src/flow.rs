@@ -0,0 +1,242 @@
+use crate::types_structs::{NodeId, VarmerGraph};
+use std::collections::VecDeque;
+
+//Cost per unit of flow through a unitig up to its coverage, and beyond it. Flow is routed
+//through a unitig while it explains more covered unitigs on its path than it overshoots.
+const BELOW_COVERAGE_COST: i64 = -1;
+const ABOVE_COVERAGE_COST: i64 = 1;
+
+//Residual network for min-cost flow. The reverse of arc i is arc i ^ 1.
+struct FlowNetwork {
+    head: Vec<usize>,
+    cap: Vec<u64>,
+    cost: Vec<i64>,
+    adj: Vec<Vec<usize>>,
+}
+
+impl FlowNetwork {
+    fn new(num_nodes: usize) -> Self {
+        FlowNetwork {
+            head: vec![],
+            cap: vec![],
+            cost: vec![],
+            adj: vec![vec![]; num_nodes],
+        }
+    }
+
+    fn add_arc(&mut self, from: usize, to: usize, cap: u64, cost: i64) -> usize {
+        let arc = self.head.len();
+        self.head.push(to);
+        self.cap.push(cap);
+        self.cost.push(cost);
+        self.adj[from].push(arc);
+        self.head.push(from);
+        self.cap.push(0);
+        self.cost.push(-cost);
+        self.adj[to].push(arc + 1);
+        arc
+    }
+
+    //Flow sent along arc, which is the residual capacity of its reverse.
+    fn flow(&self, arc: usize) -> u64 {
+        self.cap[arc ^ 1]
+    }
+
+    //Cheapest source to sink path in the residual network as arcs, by Bellman-Ford with a queue.
+    //The unitig graph is a DAG (varmers follow SNP order) so the residual network has no
+    //negative cycles.
+    fn shortest_path(&self, source: usize, sink: usize) -> Option<(i64, Vec<usize>)> {
+        let n = self.adj.len();
+        let mut dist = vec![i64::MAX; n];
+        let mut prev_arc = vec![usize::MAX; n];
+        let mut in_queue = vec![false; n];
+        let mut queue = VecDeque::new();
+        dist[source] = 0;
+        queue.push_back(source);
+        while let Some(u) = queue.pop_front() {
+            in_queue[u] = false;
+            for &arc in self.adj[u].iter() {
+                let v = self.head[arc];
+                if self.cap[arc] > 0 && dist[u] + self.cost[arc] < dist[v] {
+                    dist[v] = dist[u] + self.cost[arc];
+                    prev_arc[v] = arc;
+                    if !in_queue[v] {
+                        in_queue[v] = true;
+                        queue.push_back(v);
+                    }
+                }
+            }
+        }
+        if dist[sink] == i64::MAX {
+            return None;
+        }
+        let mut arcs = vec![];
+        let mut v = sink;
+        while v != source {
+            let arc = prev_arc[v];
+            arcs.push(arc);
+            v = self.head[arc ^ 1];
+        }
+        arcs.reverse();
+        Some((dist[sink], arcs))
+    }
+}
+
+//Candidate haplotype paths from a min-cost flow on the unitig graph. Each unitig v is split
+//into v_in -> v_out with a cheap arc of capacity equal to its coverage and an expensive arc of
+//unbounded capacity; graph edges join v_out to the next unitig's v_in. Flow enters at unitigs
+//without in-edges and leaves at unitigs without out-edges. Flow is augmented along the
+//cheapest path while it lowers the cost, then decomposed greedily into paths along the edges
+//with the most flow. Paths with flow < min_flow are dropped. Every unitig of a path is paired
+//with the flow of the path.
+pub fn flow_paths(graph: &VarmerGraph, min_flow: u64) -> Vec<Vec<(NodeId, usize)>> {
+    let n = graph.len();
+    if n == 0 {
+        return vec![];
+    }
+    let source = 2 * n;
+    let sink = 2 * n + 1;
+    let unbounded = graph.coverage.iter().sum::<u64>() + 1;
+    let mut network = FlowNetwork::new(2 * n + 2);
+
+    let mut source_arcs = vec![];
+    let mut sink_arcs = vec![usize::MAX; n];
+    let mut edge_arcs = vec![vec![]; n];
+    for v in graph.ids() {
+        let vi = v as usize;
+        network.add_arc(2 * vi, 2 * vi + 1, graph.coverage[vi], BELOW_COVERAGE_COST);
+        network.add_arc(2 * vi, 2 * vi + 1, unbounded, ABOVE_COVERAGE_COST);
+        if graph.in_edges[vi].is_empty() {
+            source_arcs.push((v, network.add_arc(source, 2 * vi, unbounded, 0)));
+        }
+        if graph.out_edges[vi].is_empty() {
+            sink_arcs[vi] = network.add_arc(2 * vi + 1, sink, unbounded, 0);
+        }
+    }
+    for v in graph.ids() {
+        for &w in graph.out_edges[v as usize].iter() {
+            let arc = network.add_arc(2 * v as usize + 1, 2 * w as usize, unbounded, 0);
+            edge_arcs[v as usize].push((w, arc));
+        }
+    }
+
+    let mut total_flow = 0;
+    while let Some((cost, arcs)) = network.shortest_path(source, sink) {
+        if cost >= 0 {
+            break;
+        }
+        let bottleneck = arcs.iter().map(|arc| network.cap[*arc]).min().unwrap();
+        for arc in arcs {
+            network.cap[arc] -= bottleneck;
+            network.cap[arc ^ 1] += bottleneck;
+        }
+        total_flow += bottleneck;
+    }
+    log::debug!("Total flow through unitig graph: {}", total_flow);
+
+    let mut remaining = vec![0; network.head.len()];
+    for arc in (0..network.head.len()).step_by(2) {
+        remaining[arc] = network.flow(arc);
+    }
+
+    let mut paths = vec![];
+    loop {
+        let start = source_arcs
+            .iter()
+            .filter(|(_, arc)| remaining[*arc] > 0)
+            .max_by_key(|(v, arc)| (remaining[*arc], std::cmp::Reverse(*v)));
+        let Some(&(start, start_arc)) = start else {
+            break;
+        };
+        let mut nodes = vec![start];
+        let mut arcs = vec![start_arc];
+        let mut v = start as usize;
+        loop {
+            let next = edge_arcs[v]
+                .iter()
+                .filter(|(_, arc)| remaining[*arc] > 0)
+                .max_by_key(|(w, arc)| (remaining[*arc], std::cmp::Reverse(*w)));
+            let to_sink = sink_arcs[v] != usize::MAX && remaining[sink_arcs[v]] > 0;
+            match next {
+                Some(&(w, arc)) if !to_sink || remaining[arc] > remaining[sink_arcs[v]] => {
+                    nodes.push(w);
+                    arcs.push(arc);
+                    v = w as usize;
+                }
+                _ => {
+                    //Flow is conserved, so flow into a unitig without flow out ends at the sink.
+                    debug_assert!(to_sink, "Flow path stuck at unitig {}", v);
+                    arcs.push(sink_arcs[v]);
+                    break;
+                }
+            }
+        }
+        let weight = arcs.iter().map(|arc| remaining[*arc]).min().unwrap();
+        for arc in arcs {
+            remaining[arc] -= weight;
+        }
+        log::trace!("FLOW PATH: {} UNITIGS, FLOW {}", nodes.len(), weight);
+        if weight < min_flow {
+            continue;
+        }
+        paths.push(nodes.into_iter().map(|v| (v, weight as usize)).collect::<Vec<_>>());
+    }
+
+    log::debug!("Number of candidate flow paths passing filters: {}", paths.len());
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Graph with nodes of the given coverages and edges. Varmers are placeholders; flow_paths
+    //only uses coverages and edges.
+    fn graph(coverages: &[u64], edges: &[(NodeId, NodeId)]) -> VarmerGraph {
+        let varmers = (0..coverages.len() as u32).map(|i| vec![(i + 1, 0)]).collect::<Vec<_>>();
+        let mut graph = VarmerGraph::from_nodes(
+            varmers
+                .iter()
+                .map(|x| x.as_slice())
+                .zip(coverages.iter().copied()),
+        );
+        for (from, to) in edges.iter() {
+            graph.add_edge(*from, *to);
+        }
+        graph
+    }
+
+    fn nodes(path: &[(NodeId, usize)]) -> Vec<NodeId> {
+        path.iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn haplotypes_sharing_a_unitig() {
+        //0 -> 2 -> 3 and 1 -> 2 -> 4, sharing unitig 2.
+        let graph = graph(&[10, 5, 15, 10, 5], &[(0, 2), (1, 2), (2, 3), (2, 4)]);
+        let paths = flow_paths(&graph, 1);
+        assert_eq!(paths.len(), 2);
+        assert_eq!(nodes(&paths[0]), vec![0, 2, 3]);
+        assert!(paths[0].iter().all(|x| x.1 == 10));
+        assert_eq!(nodes(&paths[1]), vec![1, 2, 4]);
+        assert!(paths[1].iter().all(|x| x.1 == 5));
+    }
+
+    #[test]
+    fn low_abundance_path_across_bubbles() {
+        //A major (30x) and a minor (3x) haplotype differ in two bubbles joined by unitig 3. Reads
+        //of the minor haplotype only need to span one bubble; the flow links them through 3.
+        let graph = graph(
+            &[33, 30, 3, 33, 30, 3, 33],
+            &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 4), (3, 5), (4, 6), (5, 6)],
+        );
+        let paths = flow_paths(&graph, 2);
+        assert_eq!(paths.len(), 2);
+        assert_eq!(nodes(&paths[0]), vec![0, 1, 3, 4, 6]);
+        assert!(paths[0].iter().all(|x| x.1 == 30));
+        assert_eq!(nodes(&paths[1]), vec![0, 2, 3, 5, 6]);
+        assert!(paths[1].iter().all(|x| x.1 == 3));
+
+        assert_eq!(flow_paths(&graph, 4).len(), 1);
+    }
+}
@@ -12,4 +12,12 @@ pub mod parse_cmd_line;
 
 pub mod dbg;
 
+pub mod flow;
+
+pub mod bootstrap;
+
+pub mod recombination;
+
 pub mod consensus;
+
+pub mod variant_caller;
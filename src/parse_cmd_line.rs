@@ -9,7 +9,14 @@ pub enum Preset{
     HiFi,
 }
 
-#[derive(Parser, Debug)]
+#[derive(clap::ValueEnum, Clone, Debug, Default)]
+pub enum PathMode{
+    #[default]
+    ReadPaths,
+    Flow,
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(name = "devider", version, about = "Long-read haplotyping for diverse small sequences (e.g. viruses, genes).", long_about = None)]
 pub struct Options{
 
@@ -23,13 +30,16 @@ pub struct Options{
     pub preset: Preset,
 
     //heading = input?
-    /// Indexed bam file to phase.
-    #[arg(short, long, help_heading = "INPUT")]
-    pub bam_file: String,
-
-    /// VCF file with SNPs.
+    /// Indexed BAM or CRAM file to phase. CRAM is decoded with --reference-fasta. Several
+    /// files (e.g. different runs of the same sample) can be given; their reads are pooled.
+    #[arg(short, long, num_args = 1.., required = true, help_heading = "INPUT")]
+    pub bam_file: Vec<String>,
+
+    /// VCF/BCF file with variants. SNPs, MNPs and indels are phased. If not provided, variants
+    /// are called from the BAM file (see VARIANT CALLING options). If indexed (tbi/csi), only
+    /// the --bed-file or -S regions are read.
     #[arg(short, long, help_heading = "INPUT")]
-    pub vcf_file: String,
+    pub vcf_file: Option<String>,
 
     /// Reference fasta file.
     #[arg(short, long, help_heading = "INPUT")]
@@ -49,6 +59,12 @@ pub struct Options{
     #[arg(long, help_heading = "INPUT")]
     pub bed_file: Option<String>,
 
+    /// Phase reads separately for each value of this tag (e.g. RG, BC, CB). Each group is
+    /// written to its own subdirectory and abundances of all groups to group_abundances.tsv.
+    /// Reads without the tag are skipped.
+    #[arg(long, help_heading = "INPUT")]
+    pub split_tag: Option<String>,
+
     /// Do not use supplementary alignments.
     #[arg(long, help_heading = "OPTIONS")]
     pub dont_use_supp_aln: bool,
@@ -74,6 +90,22 @@ pub struct Options{
     #[arg(short, help_heading = "ALGORITHM")]
     pub k: Option<usize>,
 
+    /// Grow k from the starting k (-k or automatic) up to the median # of SNPs per read, 
+    /// stopping once the number of unitigs stabilizes or the graph starts to fragment.
+    #[arg(long, help_heading = "ALGORITHM")]
+    pub multi_k: bool,
+
+    /// Let k-mers span SNPs a read has no allele for (deletions, low quality bases) by 
+    /// matching them to the k-mers of other reads.
+    #[arg(long, help_heading = "ALGORITHM")]
+    pub gapped_varmers: bool,
+
+    /// How candidate haplotypes are extracted from the unitig graph. read-paths: merge the 
+    /// unitig paths that reads align to. flow: decompose a flow fit to unitig coverages into 
+    /// weighted paths, which can recover haplotypes that no read spans.
+    #[arg(long, value_enum, default_value_t = PathMode::ReadPaths, help_heading = "ALGORITHM")]
+    pub path_mode: PathMode,
+
     /// Don't use primary mappings with < --mapq-cutoff.
     #[arg(long, default_value_t = 5, help_heading = "OPTIONS")]
     pub mapq_cutoff: u8,
@@ -107,14 +139,46 @@ pub struct Options{
     #[arg(long, default_value_t = 5., help_heading = "ALGORITHM")]
     pub min_cov: f64,
 
+    /// Minimum HAPQ (0-40) of a haplotype to be considered. HAPQ is based on the reads
+    /// supporting a haplotype uniquely, its distance to the nearest other haplotype and the
+    /// fraction of its alleles in the unitig graph.
+    #[arg(long, default_value_t = crate::constants::HAPQ_CUTOFF, help_heading = "ALGORITHM")]
+    pub min_hapq: u8,
+
+    /// Reads whose most probable haplotype has a posterior below this are ambiguous. Ambiguous 
+    /// reads do not count toward candidate haplotypes or HAPQ, but are still assigned to their 
+    /// most probable haplotype in the outputs. Posteriors are written to read_posteriors.tsv.
+    #[arg(long, default_value_t = 0.9, help_heading = "ALGORITHM")]
+    pub min_posterior: f64,
+
+    /// Rerun phasing on this many subsamples of 80% of the reads (seeded by --seed) and write how 
+    /// often each haplotype is recovered and a 95% interval of its abundance to bootstrap.tsv.
+    #[arg(long, default_value_t = 0, help_heading = "ALGORITHM")]
+    pub bootstrap: usize,
+
     /// Minimum base quality to consider for fastq.
     #[arg(long, default_value_t = 3, help_heading = "OPTIONS")]
     pub min_qual: u8,
 
-    /// Maximum number of alignments per contig.
+    /// Maximum number of alignments per contig. The first alignments by position are kept
+    /// unless --random-downsample is set.
     #[arg(long, default_value_t = 1000000000000, help_heading = "OPTIONS")]
     pub max_frags: usize,
 
+    /// Keep a random subset of --max-frags alignments instead of the first ones.
+    #[arg(long, help_heading = "OPTIONS")]
+    pub random_downsample: bool,
+
+    /// Downsample alignments so each SNP is covered by at least this many reads (or all of its
+    /// reads); SNPs can keep more. Reads are picked in random order without looking at their
+    /// alleles.
+    #[arg(long, help_heading = "OPTIONS")]
+    pub max_snp_depth: Option<usize>,
+
+    /// Random seed for downsampling.
+    #[arg(long, default_value_t = 0, help_heading = "OPTIONS")]
+    pub seed: u64,
+
     /// No base realignment against SNPs.
     #[arg(long, help_heading = "OPTIONS")]
     pub no_realign: bool,
@@ -125,7 +189,47 @@ pub struct Options{
 
     /// FDR for strand bias filtering.
     #[arg(long, default_value_t = 0.005, help_heading = "ALGORITHM")]
-    pub strand_bias_fdr: f64
+    pub strand_bias_fdr: f64,
+
+    /// Minimum alternate allele frequency for calling variants without --vcf-file.
+    #[arg(long, default_value_t = 0.02, help_heading = "VARIANT CALLING")]
+    pub call_min_af: f64,
+
+    /// Minimum depth for calling variants without --vcf-file.
+    #[arg(long, default_value_t = 10, help_heading = "VARIANT CALLING")]
+    pub call_min_depth: usize,
+
+    /// Also call indels without --vcf-file. Indel errors are common in nanopore reads, so
+    /// consider a higher --call-min-af.
+    #[arg(long, help_heading = "VARIANT CALLING")]
+    pub call_indels: bool,
+
+    /// Only use VCF records with FILTER PASS (or missing).
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_pass_only: bool,
+
+    /// Only use VCF records with QUAL >= this value.
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_min_qual: Option<f32>,
+
+    /// Only use VCF records with alternate allele frequency >= this value. Taken from
+    /// FORMAT/AF or FORMAT/AD of the sample if present, otherwise INFO/AF.
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_min_af: Option<f64>,
+
+    /// Only use VCF records with alternate allele frequency <= this value.
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_max_af: Option<f64>,
+
+    /// Only use VCF records with depth >= this value. Taken from FORMAT/DP of the sample if
+    /// present, otherwise INFO/DP.
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_min_dp: Option<i32>,
+
+    /// Sample to use for multi-sample VCFs. Only records where this sample has a
+    /// non-reference genotype are used.
+    #[arg(long, help_heading = "VCF FILTERING")]
+    pub vcf_sample: Option<String>,
 }
 
 
@@ -0,0 +1,195 @@
+use crate::constants;
+use crate::parse_cmd_line::Options;
+use crate::types_structs::*;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+//A haplotype as a mosaic of two parents: it follows first before the breakpoint SNP and second
+//from it on. previous_informative is the last SNP before the breakpoint where the parents
+//differ, so the switch happens between the two. distance is the fraction of shared sites at
+//which the haplotype differs from the mosaic.
+struct Mosaic {
+    first: usize,
+    second: usize,
+    breakpoint: SnpPosition,
+    previous_informative: SnpPosition,
+    distance: f64,
+    llr: f64,
+}
+
+//Test each haplotype against ordered pairs of more abundant haplotypes as parents of a single
+//crossover. The score is the log-likelihood ratio of the best mosaic against the better of its
+//two parents alone, with allele mismatches at rate constants::RECOMBINANT_ALLELE_ERROR and a
+//ln(sites - 1) penalty for choosing the breakpoint. Haplotypes whose best mosaic scores at least
+//constants::RECOMBINANT_MIN_LLR and fits within constants::RECOMBINANT_MAX_DISTANCE are flagged as
+//recombinant; a distinct strain can score highly while matching neither parent. Tested
+//haplotypes are appended to recombinants.tsv; haplotypes with fewer than two more abundant ones
+//are not tested.
+pub fn detect_recombinants(
+    final_results: &mut [HapFinalResult],
+    snp_pos_to_genome_pos: &[usize],
+    options: &Options,
+    contig_range: (&str, Option<(usize, usize)>),
+) {
+    let mut mosaics = vec![];
+    for (i, res) in final_results.iter().enumerate() {
+        let parents = (0..final_results.len())
+            .filter(|j| *j != i && final_results[*j].relative_abundances > res.relative_abundances)
+            .collect::<Vec<usize>>();
+        let mut best: Option<Mosaic> = None;
+        for &first in parents.iter() {
+            for &second in parents.iter() {
+                if first == second {
+                    continue;
+                }
+                let mosaic = best_mosaic(
+                    &res.path_frag,
+                    &final_results[first].path_frag,
+                    &final_results[second].path_frag,
+                );
+                if let Some(mosaic) = mosaic {
+                    if best.as_ref().is_none_or(|x| mosaic.llr > x.llr) {
+                        best = Some(Mosaic { first, second, ..mosaic });
+                    }
+                }
+            }
+        }
+        mosaics.push(best);
+    }
+
+    let (start, end) = match contig_range.1 {
+        Some((s, e)) => (format!("{}", s), format!("{}", e)),
+        None => (String::from("ALL"), String::from("ALL")),
+    };
+    let recombinant_file = Path::new(&options.output_dir).join("recombinants.tsv");
+    let exists = recombinant_file.exists();
+    let mut recombinant_writer = BufWriter::new(
+        std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&recombinant_file)
+            .expect("Could not open recombinant file"),
+    );
+    if !exists {
+        recombinant_writer
+            .write_all(b"#contig\trange\thaplotype\tparent_1\tparent_2\tbreakpoint_snp\tbreakpoint_interval\tmosaic_distance\tllr\trecombinant\n")
+            .unwrap();
+    }
+    let genome_pos = |snp_pos: SnpPosition| snp_pos_to_genome_pos[snp_pos as usize - 1] + 1;
+    for (i, (res, mosaic)) in final_results.iter_mut().zip(mosaics).enumerate() {
+        let Some(mosaic) = mosaic else {
+            continue;
+        };
+        let recombinant = mosaic.llr >= constants::RECOMBINANT_MIN_LLR
+            && mosaic.distance <= constants::RECOMBINANT_MAX_DISTANCE;
+        if recombinant {
+            log::debug!(
+                "Haplotype {} is a recombinant of {} and {} at SNP {} (LLR {:.2})",
+                i,
+                mosaic.first,
+                mosaic.second,
+                mosaic.breakpoint,
+                mosaic.llr
+            );
+            res.recombinant = Some((mosaic.first, mosaic.second));
+        }
+        writeln!(
+            recombinant_writer,
+            "{}\t{}-{}\t{}\t{}\t{}\t{}\t{}-{}\t{:.4}\t{:.2}\t{}",
+            contig_range.0,
+            start,
+            end,
+            i,
+            mosaic.first,
+            mosaic.second,
+            mosaic.breakpoint,
+            genome_pos(mosaic.previous_informative),
+            genome_pos(mosaic.breakpoint),
+            mosaic.distance,
+            mosaic.llr,
+            if recombinant { "yes" } else { "no" }
+        )
+        .unwrap();
+    }
+}
+
+//Best single crossover from first to second for hap over the sites all three have alleles for,
+//with the parent indices left unset. None if the parents differ at no site on one side of the
+//crossover.
+fn best_mosaic(hap: &DictFrag, first: &DictFrag, second: &DictFrag) -> Option<Mosaic> {
+    let sites = hap
+        .seq_vec
+        .iter()
+        .filter_map(|(pos, geno)| {
+            let first_geno = first.seq.get(pos)?;
+            let second_geno = second.seq.get(pos)?;
+            Some((*pos, geno != first_geno, geno != second_geno, first_geno != second_geno))
+        })
+        .collect::<Vec<_>>();
+    if sites.len() < 2 {
+        return None;
+    }
+
+    //Mismatches to first before each site and to second from each site on.
+    let mut first_before = vec![0; sites.len() + 1];
+    for (t, site) in sites.iter().enumerate() {
+        first_before[t + 1] = first_before[t] + site.1 as usize;
+    }
+    let mut second_from = vec![0; sites.len() + 1];
+    for (t, site) in sites.iter().enumerate().rev() {
+        second_from[t] = second_from[t + 1] + site.2 as usize;
+    }
+
+    let crossover = (1..sites.len()).min_by_key(|t| first_before[*t] + second_from[*t])?;
+    let breakpoint = sites[crossover..].iter().find(|x| x.3)?.0;
+    let previous_informative = sites[..crossover].iter().rev().find(|x| x.3)?.0;
+
+    let mosaic_mismatches = first_before[crossover] + second_from[crossover];
+    let single_mismatches = first_before[sites.len()].min(second_from[0]);
+    let error = constants::RECOMBINANT_ALLELE_ERROR;
+    let llr = (single_mismatches as f64 - mosaic_mismatches as f64) * ((1. - error) / error).ln()
+        - ((sites.len() - 1) as f64).ln();
+    Some(Mosaic {
+        first: 0,
+        second: 0,
+        breakpoint,
+        previous_informative,
+        distance: mosaic_mismatches as f64 / sites.len() as f64,
+        llr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict_frag(seq_vec: Vec<(SnpPosition, Genotype)>) -> DictFrag {
+        DictFrag {
+            seq: seq_vec.iter().copied().collect(),
+            first_position: seq_vec[0].0,
+            last_position: seq_vec.last().unwrap().0,
+            seq_vec,
+            cov: 0,
+        }
+    }
+
+    #[test]
+    fn best_mosaic_finds_two_parent_crossover() {
+        //The parents share SNPs 9-12, so the crossover lies between SNPs 8 and 13.
+        let first = dict_frag((1..=20).map(|pos| (pos, 0)).collect());
+        let second = dict_frag((1..=20).map(|pos| (pos, !(9..=12).contains(&pos) as Genotype)).collect());
+        let hap = dict_frag((1..=20).map(|pos| (pos, (pos > 12) as Genotype)).collect());
+
+        let mosaic = best_mosaic(&hap, &first, &second).unwrap();
+        assert_eq!((mosaic.breakpoint, mosaic.previous_informative), (13, 8));
+        assert_eq!(mosaic.distance, 0.);
+        let error = constants::RECOMBINANT_ALLELE_ERROR;
+        let llr = 8. * ((1. - error) / error).ln() - 19f64.ln();
+        assert!((mosaic.llr - llr).abs() < 1e-9);
+        assert!(mosaic.llr >= constants::RECOMBINANT_MIN_LLR);
+
+        //A copy of one parent is not a better fit as a mosaic.
+        let mosaic = best_mosaic(&first, &first, &second).unwrap();
+        assert!(mosaic.llr < 0.);
+    }
+}
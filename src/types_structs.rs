@@ -1,9 +1,9 @@
 use ordered_float::*;
 use std::rc::Rc;
-use std::sync::Arc;
 use debruijn::dna_string::DnaString;
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
+use rayon::prelude::*;
 use rust_htslib::bam::Record;
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
@@ -24,6 +24,7 @@ pub const BYTE_TO_SEQ: [u8; 256] = [
 pub type GnPosition = usize;
 pub type SnpPosition = u32;
 pub type Genotype = u8;
+pub type Allele = Vec<u8>;
 pub type GenotypeCount = OrderedFloat<f64>;
 pub type Haplotype = FxHashMap<SnpPosition, FxHashMap<Genotype, GenotypeCount>>;
 pub type VarMer = Vec<(SnpPosition, Genotype)>;
@@ -39,11 +40,54 @@ pub enum GraphConstraint {
 
 #[derive(Debug, Clone, Default)]
 pub struct VcfProfile<'a> {
-    pub vcf_pos_allele_map: FxHashMap<&'a str, FxHashMap<GnPosition, Vec<Genotype>>>,
+    pub vcf_pos_allele_map: FxHashMap<&'a str, FxHashMap<GnPosition, Vec<Allele>>>,
     pub vcf_pos_to_snp_counter_map: FxHashMap<&'a str, FxHashMap<GnPosition, SnpPosition>>,
     pub vcf_snp_pos_to_gn_pos_map: FxHashMap<&'a str, Vec<GnPosition>>,
 }
 
+impl<'a> VcfProfile<'a> {
+    //Add a variant site to the profile. Sites must be added in order of position for each
+    //contig; a site at the same position as the previous one has its alleles merged in.
+    pub fn add_variant(&mut self, contig: &'a str, pos: GnPosition, alleles: Vec<Allele>) {
+        let pos_allele_map = self.vcf_pos_allele_map.entry(contig).or_default();
+        let pos_to_snp_counter_map = self.vcf_pos_to_snp_counter_map.entry(contig).or_default();
+        let snp_pos_to_gn_pos_map = self.vcf_snp_pos_to_gn_pos_map.entry(contig).or_default();
+
+        if snp_pos_to_gn_pos_map.last() == Some(&pos) {
+            log::debug!("VCF : Variant at position {} is too close to previous variant. Merging alleles.", pos);
+            merge_alleles(pos_allele_map.get_mut(&pos).unwrap(), alleles);
+            return;
+        }
+        pos_to_snp_counter_map.insert(pos, snp_pos_to_gn_pos_map.len() as SnpPosition);
+        snp_pos_to_gn_pos_map.push(pos);
+        pos_allele_map.insert(pos, alleles);
+    }
+}
+
+//Merge the alleles of a record into the alleles of a previous record at the same position.
+//REF alleles of different length (e.g. a SNP and a deletion at the same position) are made
+//consistent by padding the shorter REF and its ALTs with the remaining reference bases.
+pub(crate) fn merge_alleles(alleles: &mut Vec<Allele>, mut new_alleles: Vec<Allele>) {
+    let ref_len = alleles[0].len();
+    let new_ref_len = new_alleles[0].len();
+    if new_ref_len > ref_len {
+        let suffix = new_alleles[0][ref_len..].to_vec();
+        for allele in alleles.iter_mut() {
+            allele.extend_from_slice(&suffix);
+        }
+    } else if ref_len > new_ref_len {
+        let suffix = alleles[0][new_ref_len..].to_vec();
+        for allele in new_alleles.iter_mut() {
+            allele.extend_from_slice(&suffix);
+        }
+    }
+    for allele in new_alleles.into_iter() {
+        if !alleles.contains(&allele) {
+            alleles.push(allele);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TraceBackNode {
     pub score: f64,
@@ -58,6 +102,8 @@ pub struct Frag {
     pub counter_id: usize,
     pub seq_dict: FxHashMap<SnpPosition, Genotype>,
     pub qual_dict: FxHashMap<SnpPosition, u8>,
+    //Log-likelihood (natural log) of the read given each allele at a site.
+    pub allele_lls: FxHashMap<SnpPosition, Vec<OrderedFloat<f64>>>,
     pub first_position: SnpPosition,
     pub last_position: SnpPosition,
     pub positions: FxHashSet<SnpPosition>,
@@ -67,8 +113,11 @@ pub struct Frag {
     pub snp_pos_to_seq_pos: FxHashMap<SnpPosition, (u8, GnPosition)>,
     pub first_pos_base: GnPosition,
     pub last_pos_base: GnPosition,
-    pub forward_strand: bool
-    
+    pub forward_strand: bool,
+    //Index of the input alignment file (-b) the read came from.
+    pub source: usize,
+    //Leading and trailing hard clipped bases of the alignment record.
+    pub hardclips: (usize, usize),
 }
 
 #[derive(Debug, Clone, Default, Eq, PartialEq)]
@@ -83,7 +132,9 @@ pub struct FragDBG {
     pub last_position: SnpPosition,
     pub snp_pos_to_seq_pos: FxHashMap<SnpPosition, (u8, GnPosition)>,
     pub qual_dict: FxHashMap<SnpPosition, u8>,
+    pub allele_lls: FxHashMap<SnpPosition, Vec<OrderedFloat<f64>>>,
     pub forward_strand: bool,
+    pub source: usize,
 }
 
 impl Ord for Frag{
@@ -275,6 +326,7 @@ pub fn build_frag(id: String, counter_id: usize, is_paired: bool) -> Frag {
         counter_id: counter_id,
         seq_dict: FxHashMap::default(),
         qual_dict: FxHashMap::default(),
+        allele_lls: FxHashMap::default(),
         first_position: SnpPosition::MAX,
         last_position: SnpPosition::MIN,
         positions: FxHashSet::default(),
@@ -284,7 +336,9 @@ pub fn build_frag(id: String, counter_id: usize, is_paired: bool) -> Frag {
         snp_pos_to_seq_pos: FxHashMap::default(),
         first_pos_base: GnPosition::MAX,
         last_pos_base: GnPosition::MAX,
-        forward_strand: true
+        forward_strand: true,
+        source: 0,
+        hardclips: (0, 0),
     };
 
     toret
@@ -379,11 +433,114 @@ pub fn build_truncated_hap_block(
     return (blocks_broken, HapBlock { blocks: block_vec });
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Default)]
-pub struct DBGInfo {
-    pub out_varmers: Vec<Arc<VarMer>>,
-    pub in_varmers: Vec<Arc<VarMer>>,
-    pub coverage: u64,
+pub type NodeId = u32;
+
+//Varmer graph stored as an arena. The varmers of all nodes are packed into one buffer, node i
+//being alleles[offsets[i]..offsets[i + 1]], and edges refer to nodes by ID.
+#[derive(Debug, Clone, Default)]
+pub struct VarmerGraph {
+    alleles: Vec<(SnpPosition, Genotype)>,
+    offsets: Vec<usize>,
+    //Node IDs ordered by varmer, for lookups.
+    sorted: Vec<NodeId>,
+    pub coverage: Vec<u64>,
+    pub out_edges: Vec<Vec<NodeId>>,
+    pub in_edges: Vec<Vec<NodeId>>,
+}
+
+impl VarmerGraph {
+    //Graph of the given (varmer, coverage) nodes, in order, without edges.
+    pub fn from_nodes<'a>(
+        nodes: impl IntoIterator<Item = (&'a [(SnpPosition, Genotype)], u64)>,
+    ) -> VarmerGraph {
+        let mut graph = VarmerGraph {
+            offsets: vec![0],
+            ..Default::default()
+        };
+        for (varmer, coverage) in nodes {
+            graph.alleles.extend_from_slice(varmer);
+            graph.offsets.push(graph.alleles.len());
+            graph.coverage.push(coverage);
+        }
+        let num_nodes = graph.coverage.len();
+        graph.out_edges = vec![vec![]; num_nodes];
+        graph.in_edges = vec![vec![]; num_nodes];
+        graph.sorted = (0..num_nodes as NodeId).collect();
+        let VarmerGraph { alleles, offsets, sorted, .. } = &mut graph;
+        sorted.sort_by_key(|id| &alleles[offsets[*id as usize]..offsets[*id as usize + 1]]);
+        graph
+    }
+
+    //Link each node to the nodes whose first k - 1 SNPs are its last k - 1 SNPs. Nodes are
+    //sorted by prefix and by suffix in parallel, and each node's edges are the run of nodes
+    //with a matching prefix or suffix, in ID order.
+    pub fn link_overlaps(&mut self, k: usize) {
+        let prefix = |id: NodeId| &self.node(id)[..k - 1];
+        let suffix = |id: NodeId| {
+            let varmer = self.node(id);
+            &varmer[varmer.len() - k + 1..]
+        };
+        let mut by_prefix = self.ids().collect::<Vec<NodeId>>();
+        by_prefix.par_sort_by(|a, b| prefix(*a).cmp(prefix(*b)));
+        let mut by_suffix = self.ids().collect::<Vec<NodeId>>();
+        by_suffix.par_sort_by(|a, b| suffix(*a).cmp(suffix(*b)));
+        let out_edges = self
+            .ids()
+            .into_par_iter()
+            .map(|id| ids_with_key(&by_prefix, prefix, suffix(id)))
+            .collect();
+        let in_edges = self
+            .ids()
+            .into_par_iter()
+            .map(|id| ids_with_key(&by_suffix, suffix, prefix(id)))
+            .collect();
+        self.out_edges = out_edges;
+        self.in_edges = in_edges;
+    }
+
+    pub fn add_edge(&mut self, from: NodeId, to: NodeId) {
+        self.out_edges[from as usize].push(to);
+        self.in_edges[to as usize].push(from);
+    }
+
+    pub fn len(&self) -> usize {
+        self.coverage.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.coverage.is_empty()
+    }
+
+    pub fn ids(&self) -> std::ops::Range<NodeId> {
+        0..self.len() as NodeId
+    }
+
+    //Node IDs ordered by varmer.
+    pub fn sorted_ids(&self) -> &[NodeId] {
+        &self.sorted
+    }
+
+    pub fn node(&self, id: NodeId) -> &[(SnpPosition, Genotype)] {
+        &self.alleles[self.offsets[id as usize]..self.offsets[id as usize + 1]]
+    }
+
+    pub fn find(&self, varmer: &[(SnpPosition, Genotype)]) -> Option<NodeId> {
+        self.sorted
+            .binary_search_by(|id| self.node(*id).cmp(varmer))
+            .ok()
+            .map(|i| self.sorted[i])
+    }
+}
+
+//IDs in sorted, which is ordered by key_of, whose key is key.
+fn ids_with_key<'a>(
+    sorted: &[NodeId],
+    key_of: impl Fn(NodeId) -> &'a [(SnpPosition, Genotype)],
+    key: &[(SnpPosition, Genotype)],
+) -> Vec<NodeId> {
+    let start = sorted.partition_point(|id| key_of(*id) < key);
+    let end = sorted.partition_point(|id| key_of(*id) <= key);
+    sorted[start..end].to_vec()
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -455,15 +612,30 @@ pub struct DpResult<'a> {
 pub struct HapFinalResult<'a> {
     pub relative_abundances: f64,
     pub depth: f64,
+    //Set once the haplotypes are final enough to be compared; see compute_hapqs.
+    pub hapq: Option<u8>,
+    //Abundance (in %) estimated by EM over all reads; set for the final haplotypes.
+    pub em_abundance: Option<f64>,
+    //Parents (indices into the final haplotypes) if flagged as a recombinant.
+    pub recombinant: Option<(usize, usize)>,
     pub assigned_frags: Vec<&'a FragDBG>,
     pub path_frag: DictFrag,
+    //Unitig path the haplotype was built from; after consensus, the path of the deepest of the
+    //merged haplotypes.
+    pub path: Vec<NodeId>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct HapFinalResultString {
     pub relative_abundances: f64,
     pub depth: f64,
-    pub assigned_frags: Vec<String>,
+    pub hapq: u8,
+    //Parents (indices into the final haplotypes) if flagged as a recombinant.
+    pub recombinant: Option<(usize, usize)>,
+    //(genome position, allele) of the haplotype's sites, by position.
+    pub alleles: Vec<(GnPosition, Genotype)>,
+    //(input file index, read name)
+    pub assigned_frags: Vec<(usize, String)>,
 }
 
 
@@ -1,4 +1,5 @@
 use crate::constants;
+use crate::parse_cmd_line::Options;
 use crate::types_structs::FragDBG;
 use crate::types_structs::Frag;
 use crate::types_structs::{Genotype, GenotypeCount, Haplotype, SnpPosition};
@@ -6,6 +7,9 @@ use crate::types_structs::{HapBlock, GAP_CHAR};
 use fxhash::{FxHashMap, FxHashSet};
 use itertools::Itertools; // 0.8.2
 use ordered_float::OrderedFloat;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use rayon::prelude::*;
 use statrs::distribution::ChiSquared;
 use statrs::distribution::ContinuousCDF;
@@ -167,17 +171,27 @@ pub fn get_consensus_seq_dict(haplo: &Haplotype) -> FxHashMap<SnpPosition, Genot
     return consensus_seq_dict;
 }
 
+//With use_phred, each read adds its posterior probability of every allele at a site instead
+//of a count for its called allele.
 pub fn fragdbg_to_seq_dict(frag_set: &[&FragDBG], use_phred: bool) -> Haplotype {
     let mut hap_map = FxHashMap::default();
     for frag in frag_set.iter() {
         for pos in frag.seq_dict.keys(){
             let var_at_pos = frag.seq_dict.get(pos).unwrap();
             let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(OrderedFloat(0.));
             if use_phred {
-                *site_counter += phred_scale_dbg(frag, pos);
+                if let Some(lls) = frag.allele_lls.get(pos) {
+                    for geno in 0..lls.len() {
+                        let post = allele_posterior_dbg(frag, pos, geno as Genotype);
+                        if post > 0. {
+                            *sites.entry(geno as Genotype).or_insert(OrderedFloat(0.)) += post;
+                        }
+                    }
+                } else {
+                    *sites.entry(*var_at_pos).or_insert(OrderedFloat(0.)) += phred_scale_dbg(frag, pos);
+                }
             } else {
-                *site_counter += 1.;
+                *sites.entry(*var_at_pos).or_insert(OrderedFloat(0.)) += 1.;
             }
         }
     }
@@ -248,6 +262,68 @@ pub fn get_length_gn(all_frags: &Vec<Frag>) -> SnpPosition {
     }
 }
 
+//Downsample reads before phasing; the kept reads stay in their original order.
+//--max-snp-depth visits reads in a random order and keeps a read if it covers a SNP that still
+//has fewer than max-snp-depth reads. Every SNP keeps at least min(depth, max-snp-depth) reads;
+//a read kept for one SNP counts at all the SNPs it covers, so SNPs can end up above the cap.
+//Reads are chosen without looking at their alleles, but haplotypes whose reads cover SNPs
+//differently can still be kept at different rates. --max-frags then keeps the first reads by
+//position, or a random subset with --random-downsample.
+pub fn downsample_frags(dbg_frags: Vec<FragDBG>, options: &Options) -> Vec<FragDBG> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut keep = vec![true; dbg_frags.len()];
+
+    if let Some(max_depth) = options.max_snp_depth {
+        let mut order = (0..dbg_frags.len()).collect::<Vec<usize>>();
+        order.shuffle(&mut rng);
+        let mut snp_depths: FxHashMap<SnpPosition, usize> = FxHashMap::default();
+        for i in order {
+            let frag = &dbg_frags[i];
+            let under_cap = frag.seq.is_empty()
+                || frag.seq.iter().any(|(pos, _)| *snp_depths.get(pos).unwrap_or(&0) < max_depth);
+            if under_cap {
+                for (pos, _) in frag.seq.iter() {
+                    *snp_depths.entry(*pos).or_insert(0) += 1;
+                }
+            } else {
+                keep[i] = false;
+            }
+        }
+        log::debug!(
+            "Kept {} of {} reads with --max-snp-depth {}",
+            keep.iter().filter(|x| **x).count(),
+            dbg_frags.len(),
+            max_depth
+        );
+    }
+
+    let kept_inds = (0..dbg_frags.len()).filter(|i| keep[*i]).collect::<Vec<usize>>();
+    if kept_inds.len() > options.max_frags {
+        if options.random_downsample {
+            let chosen = kept_inds
+                .choose_multiple(&mut rng, options.max_frags)
+                .copied()
+                .collect::<FxHashSet<usize>>();
+            for i in kept_inds.iter() {
+                if !chosen.contains(i) {
+                    keep[*i] = false;
+                }
+            }
+        } else {
+            for i in kept_inds[options.max_frags..].iter() {
+                keep[*i] = false;
+            }
+        }
+    }
+
+    dbg_frags
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, kept)| *kept)
+        .map(|(frag, _)| frag)
+        .collect()
+}
+
 //Get the log p-value for a 1-sided binomial test. This is a asymptotically tight large deviation
 //bound. It's super accurate when k/n >> p, but relatively inaccurate when k/n is close to p. One
 //super nice thing about this approximation is that it is written as p = exp(A), so log(p) = A
@@ -811,6 +887,61 @@ pub fn phred_scale_dbg(frag: &FragDBG, pos: &SnpPosition) -> GenotypeCount {
     }
 }
 
+//Per-allele log-likelihoods of a read from the base quality of its called allele. The error
+//probability is spread evenly over the other alleles.
+pub fn qual_allele_lls(geno: Genotype, qual: u8, num_alleles: usize) -> Vec<OrderedFloat<f64>> {
+    let err = 10_f64.powf(qual as f64 / -10.).clamp(constants::MIN_ALLELE_ERROR, 0.5);
+    let other_ll = (err / (num_alleles.max(2) - 1) as f64).ln();
+    (0..num_alleles)
+        .map(|i| {
+            if i == geno as usize {
+                OrderedFloat((1. - err).ln())
+            } else {
+                OrderedFloat(other_ll)
+            }
+        })
+        .collect()
+}
+
+//Probability that the read carries geno at pos. Falls back to the base quality of the hard call
+//when the read has no likelihoods for the site.
+pub fn allele_posterior_dbg(frag: &FragDBG, pos: &SnpPosition, geno: Genotype) -> f64 {
+    if let Some(lls) = frag.allele_lls.get(pos) {
+        if (geno as usize) < lls.len() {
+            let lls = lls.iter().map(|x| x.into_inner()).collect::<Vec<f64>>();
+            return (lls[geno as usize] - log_sum_exp(&lls)).exp();
+        }
+        return 0.;
+    }
+    if frag.seq_dict.get(pos) == Some(&geno) {
+        phred_scale_dbg(frag, pos).into_inner()
+    } else {
+        0.
+    }
+}
+
+//Log-likelihood of the read's observation at pos if the read came from a haplotype with geno
+//there, up to a constant per site. Falls back to the base quality of the hard call, with the
+//error probability as the likelihood of any other allele.
+pub fn allele_ln_likelihood_dbg(frag: &FragDBG, pos: &SnpPosition, geno: Genotype) -> f64 {
+    let ll = if let Some(lls) = frag.allele_lls.get(pos) {
+        if (geno as usize) < lls.len() {
+            let lls = lls.iter().map(|x| x.into_inner()).collect::<Vec<f64>>();
+            lls[geno as usize] - log_sum_exp(&lls)
+        } else {
+            f64::NEG_INFINITY
+        }
+    } else {
+        let error = 1. - phred_scale_dbg(frag, pos).into_inner();
+        if frag.seq_dict.get(pos) == Some(&geno) {
+            (1. - error).ln()
+        } else {
+            error.ln()
+        }
+    };
+    ll.max(constants::MIN_ALLELE_ERROR.ln())
+}
+
 #[inline]
 pub fn phred_scale(frag: &Frag, pos: &SnpPosition) -> GenotypeCount {
     if constants::USE_QUAL_SCORES {
@@ -858,6 +989,7 @@ pub fn remove_monomorphic_allele(mut frags: Vec<Frag>, error: f64) -> Vec<Frag>
             if mono_alleles.contains(pos) {
                 frag.seq_dict.remove(pos);
                 frag.qual_dict.remove(pos);
+                frag.allele_lls.remove(pos);
                 frag.snp_pos_to_seq_pos.remove(pos);
                 to_rem.push(*pos);
             }
@@ -882,3 +1014,63 @@ pub fn remove_monomorphic_allele(mut frags: Vec<Frag>, error: f64) -> Vec<Frag>
     return new_frags;
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    //Reads over 60 SNPs with staggered starts and lengths, numbered by counter_id.
+    fn staggered_frags() -> Vec<FragDBG> {
+        (0..300)
+            .map(|i| {
+                let first = (i * 7 % 50) as SnpPosition + 1;
+                let last = (first + 3 + (i * 13 % 20) as SnpPosition).min(60);
+                FragDBG {
+                    counter_id: i,
+                    seq: (first..=last).map(|pos| (pos, (i % 2) as Genotype)).collect(),
+                    first_position: first,
+                    last_position: last,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    fn snp_depths(frags: &[FragDBG]) -> FxHashMap<SnpPosition, usize> {
+        let mut depths = FxHashMap::default();
+        for frag in frags.iter() {
+            for (pos, _) in frag.seq.iter() {
+                *depths.entry(*pos).or_insert(0) += 1;
+            }
+        }
+        depths
+    }
+
+    #[test]
+    fn max_snp_depth_keeps_min_depth_cap_per_snp() {
+        let frags = staggered_frags();
+        let depths = snp_depths(&frags);
+        for cap in [1, 5, 20, 1000] {
+            for seed in 0..5 {
+                let options = Options::parse_from([
+                    "devider",
+                    "-b",
+                    "reads.bam",
+                    "-r",
+                    "ref.fa",
+                    "--max-snp-depth",
+                    &cap.to_string(),
+                    "--seed",
+                    &seed.to_string(),
+                ]);
+                let kept = downsample_frags(frags.clone(), &options);
+                let kept_depths = snp_depths(&kept);
+                for (pos, depth) in depths.iter() {
+                    assert!(kept_depths.get(pos).copied().unwrap_or(0) >= (*depth).min(cap));
+                }
+                assert!(kept.windows(2).all(|x| x[0].counter_id < x[1].counter_id));
+            }
+        }
+    }
+}
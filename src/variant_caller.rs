@@ -0,0 +1,419 @@
+use crate::dbg;
+use crate::file_reader;
+use crate::parse_cmd_line::Options;
+use crate::types_structs::{merge_alleles, Allele, GnPosition, VcfProfile};
+use bio::io::fasta::IndexedReader as FastaIndexedReader;
+use fxhash::FxHashMap;
+use rust_htslib::bam::record::Cigar;
+use rust_htslib::{bam, bam::Read as DUMMY_NAME1};
+use statrs::distribution::{Binomial, DiscreteCDF};
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+
+//Family-wise error rate for the per-column sequencing error test (Bonferroni corrected).
+const CALL_ALPHA: f64 = 0.05;
+//Indel error rates are pooled over columns followed by a reference homopolymer of the same
+//length, with runs of at least this length pooled together.
+const MAX_HOMOPOLYMER_CLASS: usize = 8;
+
+//Read counts for one reference position, split by strand (0 = forward, 1 = reverse).
+#[derive(Debug, Clone, Default)]
+struct ColumnCounts {
+    bases: [[u32; 4]; 2],
+    error_sum: f64,
+    //Reads with an aligned base here, regardless of quality. Used as the indel depth.
+    spanning: [u32; 2],
+}
+
+//Strand tables are (ref fwd, alt fwd, ref rev, alt rev) read counts, one per ALT.
+#[derive(Debug, Clone)]
+struct CalledSite {
+    alleles: Vec<Allele>,
+    depth: u32,
+    alt_counts: Vec<u32>,
+    strand_tables: Vec<[u32; 4]>,
+    pvalue: f64,
+}
+
+//An ALT allele passing the depth, frequency and error tests, before the strand bias filter.
+struct CandidateAllele {
+    pos: GnPosition,
+    ref_allele: Allele,
+    alt_allele: Allele,
+    depth: u32,
+    count: u32,
+    strand_table: [u32; 4],
+    pvalue: f64,
+}
+
+//Call variants from the alignments of a region with a quality-aware pileup test, so devider
+//can be run without an external VCF. Alleles need --call-min-depth reads, --call-min-af alt
+//allele frequency, more alt reads than expected from the base qualities (SNPs) or from the
+//indel error rate of their homopolymer context (indels), and no strand bias (same test as the
+//SNP strand bias filter).
+pub fn call_variants<'a>(
+    main_bams: &mut [bam::IndexedReader],
+    chrom_seqs: &mut FastaIndexedReader<std::fs::File>,
+    regions: &'a [(String, Option<(usize, usize)>)],
+    options: &Options,
+) -> VcfProfile<'a> {
+    //Calls for all regions of a contig are pooled; BTreeMap keeps them sorted by position.
+    let mut contig_calls: Vec<(&'a str, BTreeMap<GnPosition, CalledSite>)> = vec![];
+    for (contig, range) in regions.iter() {
+        let mut ref_seq = vec![];
+        if chrom_seqs.fetch_all(contig).is_err() || chrom_seqs.read(&mut ref_seq).is_err() {
+            log::warn!("Contig {} not found in the reference; no variants called.", contig);
+            continue;
+        }
+        ref_seq.make_ascii_uppercase();
+        let calls = call_variants_region(main_bams, &ref_seq, contig, *range, options);
+        if let Some(entry) = contig_calls.iter_mut().find(|x| x.0 == contig.as_str()) {
+            entry.1.extend(calls);
+        } else {
+            contig_calls.push((contig.as_str(), calls));
+        }
+    }
+
+    let mut vcf_profile = VcfProfile::default();
+    for (contig, calls) in contig_calls.iter() {
+        log::info!("Called {} variant sites on {}", calls.len(), contig);
+        for (pos, site) in calls.iter() {
+            vcf_profile.add_variant(contig, *pos, site.alleles.clone());
+        }
+    }
+    write_called_variants(&contig_calls, options);
+    vcf_profile
+}
+
+fn call_variants_region(
+    main_bams: &mut [bam::IndexedReader],
+    ref_seq: &[u8],
+    contig: &str,
+    range: Option<(usize, usize)>,
+    options: &Options,
+) -> BTreeMap<GnPosition, CalledSite> {
+    let (start, end) = match range {
+        Some((s, e)) => (s.min(ref_seq.len()), e.min(ref_seq.len())),
+        None => (0, ref_seq.len()),
+    };
+    if start >= end {
+        log::warn!("Invalid range for {}; no variants called.", contig);
+        return BTreeMap::new();
+    }
+
+    let mut columns = vec![ColumnCounts::default(); end - start];
+    //(anchor position, REF, ALT) -> reads per strand
+    let mut indels: FxHashMap<(GnPosition, Allele, Allele), [u32; 2]> = FxHashMap::default();
+
+    for main_bam in main_bams.iter_mut() {
+        let fetched = match range {
+            Some((s, e)) => main_bam.fetch((contig, s as i64, e as i64)),
+            None => main_bam.fetch(contig),
+        };
+        if fetched.is_err() {
+            log::warn!("Could not fetch alignments for {} from an input file.", contig);
+            continue;
+        }
+
+        for record in main_bam.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            let (passed, _) = file_reader::alignment_passed_check(
+                record.flags(),
+                record.mapq(),
+                !options.dont_use_supp_aln,
+                true,
+                options.mapq_cutoff,
+            );
+            if !passed || record.tid() < 0 {
+                continue;
+            }
+            let strand = if record.is_reverse() { 1 } else { 0 };
+            let seq = record.seq().as_bytes();
+            let qual = record.qual();
+            let mut rpos = record.pos() as usize;
+            let mut qpos = 0;
+            for cig in record.cigar().iter() {
+                match cig {
+                    Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                        for i in 0..*l as usize {
+                            let gpos = rpos + i;
+                            if gpos < start || gpos >= end {
+                                continue;
+                            }
+                            let column = &mut columns[gpos - start];
+                            column.spanning[strand] += 1;
+                            let q = qual[qpos + i];
+                            let base = match seq[qpos + i] {
+                                b'A' => 0,
+                                b'C' => 1,
+                                b'G' => 2,
+                                b'T' => 3,
+                                _ => continue,
+                            };
+                            if q < options.min_qual {
+                                continue;
+                            }
+                            column.bases[strand][base] += 1;
+                            column.error_sum += 10_f64.powf(q as f64 / -10.);
+                        }
+                        rpos += *l as usize;
+                        qpos += *l as usize;
+                    }
+                    Cigar::Ins(l) => {
+                        if options.call_indels && rpos > start && rpos <= end && rpos <= ref_seq.len() {
+                            let mut alt = vec![ref_seq[rpos - 1]];
+                            alt.extend(seq[qpos..qpos + *l as usize].to_ascii_uppercase());
+                            let key = (rpos - 1, vec![ref_seq[rpos - 1]], alt);
+                            indels.entry(key).or_insert([0; 2])[strand] += 1;
+                        }
+                        qpos += *l as usize;
+                    }
+                    Cigar::Del(l) => {
+                        let del_end = rpos + *l as usize;
+                        if options.call_indels && rpos > start && rpos <= end && del_end <= ref_seq.len() {
+                            let key = (rpos - 1, ref_seq[rpos - 1..del_end].to_vec(), vec![ref_seq[rpos - 1]]);
+                            indels.entry(key).or_insert([0; 2])[strand] += 1;
+                        }
+                        rpos = del_end;
+                    }
+                    Cigar::RefSkip(l) => rpos += *l as usize,
+                    Cigar::SoftClip(l) => qpos += *l as usize,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let bonferroni = CALL_ALPHA / (end - start) as f64;
+    let mut alleles: Vec<CandidateAllele> = vec![];
+
+    for (i, column) in columns.iter().enumerate() {
+        let gpos = start + i;
+        let ref_base = match ref_seq[gpos] {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => continue,
+        };
+        let counts = (0..4)
+            .map(|b| column.bases[0][b] + column.bases[1][b])
+            .collect::<Vec<u32>>();
+        let depth = counts.iter().sum::<u32>();
+        if depth == 0 || (depth as f64) < options.call_min_depth as f64 {
+            continue;
+        }
+        //Substitution to one particular base; errors are assumed uniform over the 3 other bases.
+        let error_rate = (column.error_sum / depth as f64 / 3.).clamp(1e-6, 1. - 1e-6);
+        let mut alts = (0..4)
+            .filter(|b| *b != ref_base)
+            .filter(|b| counts[*b] as f64 >= options.call_min_af * depth as f64 && counts[*b] > 0)
+            .map(|b| (b, binomial_tail(depth as u64, counts[b] as u64, error_rate)))
+            .filter(|(_, p)| *p < bonferroni)
+            .collect::<Vec<(usize, f64)>>();
+        alts.sort_by(|a, b| counts[b.0].cmp(&counts[a.0]));
+        for (b, pvalue) in alts {
+            alleles.push(CandidateAllele {
+                pos: gpos,
+                ref_allele: vec![ref_seq[gpos]],
+                alt_allele: vec![b"ACGT"[b]],
+                depth,
+                count: counts[b],
+                strand_table: [
+                    column.bases[0][ref_base],
+                    column.bases[0][b],
+                    column.bases[1][ref_base],
+                    column.bases[1][b],
+                ],
+                pvalue,
+            });
+        }
+    }
+
+    //Reads with an indel anchored at each column, pooled by the length of the homopolymer after
+    //the anchor, where ONT indel errors concentrate.
+    let mut indel_reads = vec![0; end - start];
+    for ((anchor, _, _), strand_counts) in indels.iter() {
+        indel_reads[anchor - start] += strand_counts[0] + strand_counts[1];
+    }
+    let mut class_counts = [(0_u64, 0_u64); MAX_HOMOPOLYMER_CLASS + 1];
+    for (i, column) in columns.iter().enumerate() {
+        let class = homopolymer_class(ref_seq, start + i);
+        class_counts[class].0 += indel_reads[i] as u64;
+        class_counts[class].1 += (column.spanning[0] + column.spanning[1]) as u64;
+    }
+    let total_counts = class_counts
+        .iter()
+        .fold((0, 0), |acc, x| (acc.0 + x.0, acc.1 + x.1));
+
+    let mut indel_vec = indels.into_iter().collect::<Vec<_>>();
+    indel_vec.sort();
+    for ((anchor, ref_allele, alt_allele), strand_counts) in indel_vec {
+        let column = &columns[anchor - start];
+        let depth = column.spanning[0] + column.spanning[1];
+        let alt_count = strand_counts[0] + strand_counts[1];
+        if depth == 0
+            || (depth as f64) < options.call_min_depth as f64
+            || (alt_count as f64) < options.call_min_af * depth as f64
+        {
+            continue;
+        }
+        //Error rate of the other columns of the class, so a true indel does not raise its own
+        //threshold; all other columns if the class has no others.
+        let class = class_counts[homopolymer_class(ref_seq, anchor)];
+        let own = (indel_reads[anchor - start] as u64, depth as u64);
+        let (errors, spanning) = if class.1 > own.1 {
+            (class.0 - own.0, class.1 - own.1)
+        } else {
+            (total_counts.0 - own.0, total_counts.1 - own.1)
+        };
+        let error_rate = if spanning == 0 {
+            1. - 1e-6
+        } else {
+            (errors as f64 / spanning as f64).clamp(1e-6, 1. - 1e-6)
+        };
+        let pvalue = binomial_tail(depth as u64, alt_count as u64, error_rate);
+        if pvalue >= bonferroni {
+            log::trace!(
+                "Indel at {} with {}/{} reads not above error rate {:.4}",
+                anchor + 1,
+                alt_count,
+                depth,
+                error_rate
+            );
+            continue;
+        }
+        alleles.push(CandidateAllele {
+            pos: anchor,
+            ref_allele,
+            alt_allele,
+            depth,
+            count: alt_count,
+            strand_table: [
+                column.spanning[0].saturating_sub(strand_counts[0]),
+                strand_counts[0],
+                column.spanning[1].saturating_sub(strand_counts[1]),
+                strand_counts[1],
+            ],
+            pvalue,
+        });
+    }
+
+    let tables = alleles.iter().map(|x| x.strand_table).collect::<Vec<[u32; 4]>>();
+    let unbiased = dbg::strand_unbiased_sites(&tables, options.strand_bias_fdr);
+    let num_alleles = alleles.len();
+
+    //Each allele is tested on its own strand table, then indels are merged into the site of
+    //their anchor base.
+    let mut candidates: BTreeMap<GnPosition, CalledSite> = BTreeMap::new();
+    for (i, allele) in alleles.into_iter().enumerate() {
+        if !unbiased.contains(&i) {
+            log::trace!(
+                "Called variant at {} removed by strand bias filter: {:?}",
+                allele.pos + 1,
+                allele.strand_table
+            );
+            continue;
+        }
+        match candidates.get_mut(&allele.pos) {
+            Some(site) => {
+                let num_site_alleles = site.alleles.len();
+                merge_alleles(&mut site.alleles, vec![allele.ref_allele, allele.alt_allele]);
+                if site.alleles.len() > num_site_alleles {
+                    site.alt_counts.push(allele.count);
+                    site.strand_tables.push(allele.strand_table);
+                }
+            }
+            None => {
+                candidates.insert(
+                    allele.pos,
+                    CalledSite {
+                        alleles: vec![allele.ref_allele, allele.alt_allele],
+                        depth: allele.depth,
+                        alt_counts: vec![allele.count],
+                        strand_tables: vec![allele.strand_table],
+                        pvalue: allele.pvalue,
+                    },
+                );
+            }
+        }
+    }
+    log::debug!(
+        "{}: {} candidate variant alleles, {} removed by strand bias filter",
+        contig,
+        num_alleles,
+        num_alleles - unbiased.len()
+    );
+    candidates
+}
+
+//Length of the reference homopolymer following pos, capped at MAX_HOMOPOLYMER_CLASS.
+fn homopolymer_class(ref_seq: &[u8], pos: GnPosition) -> usize {
+    match ref_seq.get(pos + 1) {
+        Some(base) => ref_seq[pos + 1..]
+            .iter()
+            .take(MAX_HOMOPOLYMER_CLASS)
+            .take_while(|x| *x == base)
+            .count(),
+        None => 0,
+    }
+}
+
+fn binomial_tail(n: u64, k: u64, p: f64) -> f64 {
+    let binomial = Binomial::new(p, n).unwrap();
+    //P(X >= k)
+    1. - binomial.cdf(k - 1)
+}
+
+//Write the called sites as a VCF so the calls can be inspected or reused with -v.
+fn write_called_variants(contig_calls: &[(&str, BTreeMap<GnPosition, CalledSite>)], options: &Options) {
+    std::fs::create_dir_all(&options.output_dir).unwrap();
+    let vcf_file = format!("{}/called_variants.vcf", options.output_dir);
+    let mut writer = BufWriter::new(
+        std::fs::File::create(&vcf_file).unwrap_or_else(|_| panic!("Could not create VCF file {}", vcf_file)),
+    );
+    writeln!(writer, "##fileformat=VCFv4.2").unwrap();
+    writeln!(writer, "##source=devider").unwrap();
+    for (contig, _) in contig_calls.iter() {
+        writeln!(writer, "##contig=<ID={}>", contig).unwrap();
+    }
+    writeln!(writer, "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth of bases passing --min-qual\">").unwrap();
+    writeln!(writer, "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">").unwrap();
+    writeln!(writer, "##INFO=<ID=SB,Number=4,Type=Integer,Description=\"Ref fwd, alt fwd, ref rev, alt rev read counts of the first ALT\">").unwrap();
+    writeln!(writer, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO").unwrap();
+    for (contig, calls) in contig_calls.iter() {
+        for (pos, site) in calls.iter() {
+            let alts = site.alleles[1..]
+                .iter()
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .collect::<Vec<String>>()
+                .join(",");
+            let afs = site
+                .alt_counts
+                .iter()
+                .map(|x| format!("{:.4}", *x as f64 / site.depth as f64))
+                .collect::<Vec<String>>()
+                .join(",");
+            let qual = (-10. * site.pvalue.max(1e-30).log10()).min(300.);
+            writeln!(
+                writer,
+                "{}\t{}\t.\t{}\t{}\t{:.0}\tPASS\tDP={};AF={};SB={},{},{},{}",
+                contig,
+                pos + 1,
+                String::from_utf8_lossy(&site.alleles[0]),
+                alts,
+                qual,
+                site.depth,
+                afs,
+                site.strand_tables[0][0],
+                site.strand_tables[0][1],
+                site.strand_tables[0][2],
+                site.strand_tables[0][3]
+            )
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use assert_cmd::Command;
+use std::path::Path;
+
+//Haplotypes of a snp_haplotypes.fasta as "abundance\tsequence" lines, in the order they are
+//written.
+fn haplotypes(fasta: &Path) -> Vec<String> {
+    let mut haplotypes: Vec<(String, String)> = vec![];
+    for line in std::fs::read_to_string(fasta).unwrap().lines() {
+        if let Some(header) = line.strip_prefix('>') {
+            let abundance = header
+                .split(',')
+                .find_map(|x| x.strip_prefix("Abundance:"))
+                .unwrap();
+            haplotypes.push((abundance.to_string(), String::new()));
+        } else {
+            haplotypes.last_mut().unwrap().1.push_str(line.trim());
+        }
+    }
+    haplotypes
+        .into_iter()
+        .map(|(abundance, seq)| format!("{}\t{}", abundance, seq))
+        .collect()
+}
+
+//Pins the current output of the whole pipeline on the bundled HIV mixture: the haplotypes with
+//their abundances in output order, and the reads assigned to each (ids.txt). Update the files in
+//tests/data only for intended output changes.
+#[test]
+fn hiv_test_haplotypes_unchanged() {
+    let out_dir = std::env::temp_dir().join(format!("devider_hiv_test_{}", std::process::id()));
+    Command::cargo_bin("devider")
+        .unwrap()
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["-b", "hiv_test/3000_95_3.bam"])
+        .args(["-v", "hiv_test/3000_95_3.vcf.gz"])
+        .args(["-r", "hiv_test/OR483991.1.fasta"])
+        .args(["-t", "4", "-O", "-o"])
+        .arg(&out_dir)
+        .assert()
+        .success();
+
+    let expected = include_str!("data/hiv_test_haplotypes.tsv")
+        .lines()
+        .map(String::from)
+        .collect::<Vec<String>>();
+    let found = haplotypes(&out_dir.join("snp_haplotypes.fasta"));
+    let ids = std::fs::read_to_string(out_dir.join("ids.txt")).unwrap();
+    std::fs::remove_dir_all(&out_dir).unwrap();
+    assert_eq!(found, expected);
+    assert_eq!(ids, include_str!("data/hiv_test_ids.txt"));
+}